@@ -15,7 +15,7 @@ fn generate_source_lines(n: usize) -> Vec<String> {
         .collect()
 }
 
-const SIZES: [usize; 2] = [100_000, 1000_000];
+const SIZES: [usize; 2] = [100_000, 1_000_000];
 const SMALL_SIZES: [usize; 2] = [1000, 10_000];
 
 fn bench_type_character(c: &mut Criterion) {
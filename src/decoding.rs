@@ -0,0 +1,374 @@
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+const UTF8_BOM: &[u8] = &[0xef, 0xbb, 0xbf];
+const UTF16LE_BOM: &[u8] = &[0xff, 0xfe];
+const UTF16BE_BOM: &[u8] = &[0xfe, 0xff];
+const MAX_BOM_LENGTH: usize = 3;
+const TRANSCODE_CHUNK_SIZE: usize = 8192;
+
+/// Character encoding of the input.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1, where every byte is the code point of the same value.
+    Latin1,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    /// Whether a newline is always the single byte `\n`, so that
+    /// raw input can be split into lines before it is decoded.
+    pub fn is_ascii_compatible(&self) -> bool {
+        matches!(self, Encoding::Utf8 | Encoding::Latin1)
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('_', "-").as_str() {
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            "utf-16le" | "utf16le" => Ok(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" => Ok(Encoding::Utf16Be),
+            _ => Err(format!(
+                "Unknown encoding '{}', expected one of utf-8, latin1, utf-16le, utf-16be",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Latin1 => "latin1",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+        })
+    }
+}
+
+/// Turns input bytes into lines of text. Invalid input is replaced with U+FFFD
+/// instead of ending the input, and remembered so that it can be reported.
+/// Clones share whether anything was decoded lossily.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    encoding: Encoding,
+    line_terminator: u8,
+    lossy: Arc<AtomicBool>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new(Encoding::default())
+    }
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Decoder {
+        Decoder {
+            encoding,
+            line_terminator: b'\n',
+            lossy: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Splits input on NUL bytes instead of newlines, like `grep -z`.
+    pub fn with_null_data(self) -> Decoder {
+        Decoder {
+            line_terminator: b'\0',
+            ..self
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// The byte ending each line, after decoding.
+    pub fn line_terminator(&self) -> u8 {
+        self.line_terminator
+    }
+
+    pub fn is_null_data(&self) -> bool {
+        self.line_terminator == b'\0'
+    }
+
+    /// Removes the line terminator from the end of a line, and a carriage
+    /// return before a newline.
+    pub fn trim_line_terminator(&self, line: &mut Vec<u8>) {
+        if line.last() == Some(&self.line_terminator) {
+            line.pop();
+            if self.line_terminator == b'\n' && line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+    }
+
+    /// Whether any input so far was invalid in its encoding.
+    pub fn decoded_lossily(&self) -> bool {
+        self.lossy.load(Ordering::Relaxed)
+    }
+
+    /// Forgets about invalid input, before all input is read again.
+    pub fn reset_decoded_lossily(&self) {
+        self.lossy.store(false, Ordering::Relaxed);
+    }
+
+    /// Wraps a reader so that it produces UTF-8, to be split into lines and
+    /// passed to `decode_utf8`. A byte order mark at the start of the input
+    /// takes precedence over the encoding, and is removed.
+    pub fn reader<R: Read + Send + 'static>(
+        &self,
+        mut reader: R,
+    ) -> io::Result<Box<dyn Read + Send>> {
+        let (encoding, reader): (Encoding, Box<dyn Read + Send>) =
+            if self.encoding == Encoding::Latin1 {
+                // Any bytes are valid Latin-1, including what looks like a byte order mark
+                (Encoding::Latin1, Box::new(reader))
+            } else {
+                let mut start = Vec::with_capacity(MAX_BOM_LENGTH);
+                (&mut reader)
+                    .take(MAX_BOM_LENGTH as u64)
+                    .read_to_end(&mut start)?;
+                let (encoding, bom_length) = detect_bom(&start).unwrap_or((self.encoding, 0));
+                let rest = start.split_off(bom_length);
+                (encoding, Box::new(io::Cursor::new(rest).chain(reader)))
+            };
+        Ok(match encoding {
+            Encoding::Utf8 => reader,
+            _ => Box::new(TranscodingReader::new(reader, encoding, self.lossy.clone())),
+        })
+    }
+
+    /// Where the text starts in input beginning with `start`, after any
+    /// byte order mark, if the input is UTF-8 and can be used without `reader`.
+    pub fn utf8_text_start(&self, start: &[u8]) -> Option<usize> {
+        if self.encoding == Encoding::Latin1 {
+            return None;
+        }
+        match detect_bom(start) {
+            Some((Encoding::Utf8, bom_length)) => Some(bom_length),
+            None if self.encoding == Encoding::Utf8 => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Decodes UTF-8, such as a line from a reader returned by `reader`.
+    pub fn decode_utf8(&self, bytes: Vec<u8>) -> String {
+        String::from_utf8(bytes).unwrap_or_else(|e| {
+            self.lossy.store(true, Ordering::Relaxed);
+            String::from_utf8_lossy(e.as_bytes()).into_owned()
+        })
+    }
+
+    /// Decodes a line of raw input, which requires an ASCII compatible encoding.
+    pub fn decode_line(&self, bytes: Vec<u8>) -> String {
+        match self.encoding {
+            Encoding::Latin1 => bytes.into_iter().map(char::from).collect(),
+            _ => self.decode_utf8(bytes),
+        }
+    }
+}
+
+fn detect_bom(start: &[u8]) -> Option<(Encoding, usize)> {
+    if start.starts_with(UTF8_BOM) {
+        Some((Encoding::Utf8, UTF8_BOM.len()))
+    } else if start.starts_with(UTF16LE_BOM) {
+        Some((Encoding::Utf16Le, UTF16LE_BOM.len()))
+    } else if start.starts_with(UTF16BE_BOM) {
+        Some((Encoding::Utf16Be, UTF16BE_BOM.len()))
+    } else {
+        None
+    }
+}
+
+/// Converts Latin-1 or UTF-16 input into UTF-8.
+struct TranscodingReader<R> {
+    inner: R,
+    encoding: Encoding,
+    lossy: Arc<AtomicBool>,
+    /// Input that does not form a complete character yet.
+    pending_input: Vec<u8>,
+    output: Vec<u8>,
+    output_position: usize,
+}
+
+impl<R: Read> TranscodingReader<R> {
+    fn new(inner: R, encoding: Encoding, lossy: Arc<AtomicBool>) -> Self {
+        TranscodingReader {
+            inner,
+            encoding,
+            lossy,
+            pending_input: vec![],
+            output: vec![],
+            output_position: 0,
+        }
+    }
+
+    fn push_char(&mut self, c: char) {
+        let mut buffer = [0; 4];
+        self.output
+            .extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+    }
+
+    fn push_replacement(&mut self) {
+        self.lossy.store(true, Ordering::Relaxed);
+        self.push_char(char::REPLACEMENT_CHARACTER);
+    }
+
+    fn transcode_pending_input(&mut self) {
+        let input = std::mem::take(&mut self.pending_input);
+        if self.encoding == Encoding::Latin1 {
+            for byte in input {
+                self.push_char(char::from(byte));
+            }
+            return;
+        }
+        let mut units: Vec<u16> = input
+            .chunks_exact(2)
+            .map(|pair| match self.encoding {
+                Encoding::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+                _ => u16::from_le_bytes([pair[0], pair[1]]),
+            })
+            .collect();
+        // A high surrogate at the end may be completed by the next read
+        if units.last().is_some_and(|u| (0xd800..0xdc00).contains(u)) {
+            units.pop();
+        }
+        for c in char::decode_utf16(units.iter().copied()) {
+            match c {
+                Ok(c) => self.push_char(c),
+                Err(_) => self.push_replacement(),
+            }
+        }
+        self.pending_input = input[units.len() * 2..].to_vec();
+    }
+}
+
+impl<R: Read> Read for TranscodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_position == self.output.len() {
+            self.output.clear();
+            self.output_position = 0;
+            let mut input = [0; TRANSCODE_CHUNK_SIZE];
+            let bytes_read = self.inner.read(&mut input)?;
+            if bytes_read == 0 {
+                if self.pending_input.is_empty() {
+                    return Ok(0);
+                }
+                // Input ended in the middle of a character
+                self.pending_input.clear();
+                self.push_replacement();
+            } else {
+                self.pending_input.extend_from_slice(&input[..bytes_read]);
+                self.transcode_pending_input();
+            }
+        }
+        let length = cmp::min(buf.len(), self.output.len() - self.output_position);
+        buf[..length]
+            .copy_from_slice(&self.output[self.output_position..self.output_position + length]);
+        self.output_position += length;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn decode(encoding: Encoding, input: &[u8]) -> (String, bool) {
+        let decoder = Decoder::new(encoding);
+        let mut bytes = vec![];
+        decoder
+            .reader(io::Cursor::new(input.to_vec()))
+            .unwrap()
+            .read_to_end(&mut bytes)
+            .unwrap();
+        let text = decoder.decode_utf8(bytes);
+        (text, decoder.decoded_lossily())
+    }
+
+    fn utf16le(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(|u| u.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn decode_utf8() {
+        assert_eq!(
+            decode(Encoding::Utf8, b"caf\xc3\xa9\n"),
+            ("café\n".to_string(), false)
+        );
+        assert_eq!(
+            decode(Encoding::Utf8, b"\xef\xbb\xbfbom\n"),
+            ("bom\n".to_string(), false)
+        );
+        assert_eq!(
+            decode(Encoding::Utf8, b"bad \xff byte\n"),
+            ("bad \u{fffd} byte\n".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn decode_latin1() {
+        assert_eq!(
+            decode(Encoding::Latin1, b"caf\xe9\n"),
+            ("café\n".to_string(), false)
+        );
+        assert_eq!(
+            decode(Encoding::Latin1, b"\xff\xfe"),
+            ("ÿþ".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn decode_utf16() {
+        let text = "a\u{1f600}é\nb\n";
+        assert_eq!(
+            decode(Encoding::Utf16Le, &utf16le(text)),
+            (text.to_string(), false)
+        );
+        let big_endian: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_be_bytes()).collect();
+        assert_eq!(
+            decode(Encoding::Utf16Be, &big_endian),
+            (text.to_string(), false)
+        );
+
+        let mut with_bom = UTF16LE_BOM.to_vec();
+        with_bom.extend(utf16le(text));
+        assert_eq!(decode(Encoding::Utf8, &with_bom), (text.to_string(), false));
+        assert_eq!(
+            decode(Encoding::Utf16Be, &with_bom),
+            (text.to_string(), false)
+        );
+
+        let mut odd_length = utf16le("a");
+        odd_length.push(b'b');
+        assert_eq!(
+            decode(Encoding::Utf16Le, &odd_length),
+            ("a\u{fffd}".to_string(), true)
+        );
+    }
+
+    #[test]
+    fn decode_utf16_surrogate_pair_across_reads() {
+        // The first read returns the bytes checked for a byte order mark,
+        // so the next one ends in the middle of the emoji
+        let mut input = utf16le(&"x".repeat(TRANSCODE_CHUNK_SIZE / 2));
+        input.extend(utf16le("\u{1f600}"));
+        let (text, lossy) = decode(Encoding::Utf16Le, &input);
+        assert!(text.ends_with("x\u{1f600}"));
+        assert!(!lossy);
+    }
+}
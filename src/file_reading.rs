@@ -1,37 +1,779 @@
-use std::fs::File;
+use std::fs::{self, File, Metadata};
 use std::io;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::process::{Command, ExitStatus, Stdio};
 
 use anyhow::{Context, Result};
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use ignore::WalkBuilder;
+use memmap2::Mmap;
+use xz2::read::XzDecoder;
 
-#[derive(Debug, Clone)]
+use crate::decoding::Decoder;
+
+/// Upper bound of lines sent in one batch by `read_lines_in_batches`.
+const MAX_BATCH_SIZE: usize = 10_000;
+
+/// Magic bytes at the start of compressed data, for each supported format.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const MAX_MAGIC_LENGTH: usize = 6;
+
+/// Files smaller than this are read into memory rather than mapped, so that
+/// only files too large to read that way risk SIGBUS when truncated.
+const MIN_MAPPED_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
 pub enum SourceInput {
     FullInput(Vec<String>),
     FilePath(String),
+    /// Several files, read one after the other.
+    FilePaths(Vec<String>),
+    /// Directory trees, walked recursively. Ignored, hidden and binary files are skipped.
+    Directories(Vec<String>),
+    /// Input that is read line by line while the UI is running, e.g. a pipe.
+    Stream(File),
+    /// A shell command, whose output is read each time it is run.
+    Command(String),
+}
+
+/// Output of a `Command` input.
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub stdout: Vec<String>,
+    pub stderr: Vec<String>,
+    pub status: ExitStatus,
 }
 
 pub struct SourceProducer {
     pub input: SourceInput,
+    pub decoder: Decoder,
 }
 
 impl SourceProducer {
     pub fn get_source(&self) -> Result<Vec<String>> {
         match &self.input {
-            SourceInput::FilePath(path) => read_source_from_file(path.as_str())
+            SourceInput::FilePath(path) => read_source_from_file(path.as_str(), &self.decoder)
                 .with_context(|| format!("Failed to open file '{}'", path)),
+            SourceInput::FilePaths(_) | SourceInput::Directories(_) => Ok(self
+                .get_source_files()?
+                .into_iter()
+                .flat_map(|(_, lines)| lines)
+                .collect()),
             SourceInput::FullInput(full_input) => Ok(full_input.clone()),
+            SourceInput::Stream(_) => Ok(vec![]),
+            SourceInput::Command(_) => Ok(self.run_command()?.map_or(vec![], |o| o.stdout)),
+        }
+    }
+
+    /// Runs a `Command` input with `sh`, and waits for it to exit.
+    pub fn run_command(&self) -> Result<Option<CommandOutput>> {
+        let command = match &self.input {
+            SourceInput::Command(command) => command,
+            _ => return Ok(None),
+        };
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run command '{}'", command))?;
+        let decode = |bytes: Vec<u8>| -> io::Result<Vec<String>> {
+            let reader = BufReader::new(self.decoder.reader(io::Cursor::new(bytes))?);
+            read_lines(reader, &self.decoder)
+        };
+        Ok(Some(CommandOutput {
+            stdout: decode(output.stdout)?,
+            stderr: decode(output.stderr)?,
+            status: output.status,
+        }))
+    }
+
+    /// Returns the lines of each input file, together with its path.
+    pub fn get_source_files(&self) -> Result<Vec<(String, Vec<String>)>> {
+        match &self.input {
+            SourceInput::Directories(paths) => Ok(walk_directories(paths)?
+                .into_iter()
+                .filter_map(|path| match read_text_file(path.as_str(), &self.decoder) {
+                    // Unreadable and binary files are skipped, like ripgrep does
+                    Ok(Some(lines)) => Some((path, lines)),
+                    Ok(None) | Err(_) => None,
+                })
+                .collect()),
+            _ => self
+                .file_paths()
+                .into_iter()
+                .map(|path| {
+                    let lines = read_source_from_file(path.as_str(), &self.decoder)
+                        .with_context(|| format!("Failed to open file '{}'", path))?;
+                    Ok((path, lines))
+                })
+                .collect(),
+        }
+    }
+
+    /// Maps a large `FilePath` input into memory, if it can be used without
+    /// decompressing or transcoding it. Returns the map and the offset where
+    /// the text starts, after any byte order mark.
+    pub fn map_file(&self) -> Result<Option<(Mmap, usize)>> {
+        let path = match &self.input {
+            SourceInput::FilePath(path) => path,
+            _ => return Ok(None),
+        };
+        let file = File::open(path).with_context(|| format!("Failed to open file '{}'", path))?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() || metadata.len() < MIN_MAPPED_FILE_SIZE {
+            return Ok(None);
+        }
+        // Safety: the map is only read. If another process truncates the file
+        // while it is mapped, reading the lost part raises SIGBUS, see
+        // `Source::mapped`.
+        let map = unsafe { Mmap::map(&file) }
+            .with_context(|| format!("Failed to map file '{}'", path))?;
+        if detect_compression(&map).is_some() {
+            return Ok(None);
+        }
+        Ok(self
+            .decoder
+            .utf8_text_start(&map)
+            .map(|text_start| (map, text_start)))
+    }
+
+    /// The paths given as input, if the input consists of multiple files.
+    pub fn file_paths(&self) -> Vec<String> {
+        match &self.input {
+            SourceInput::FilePaths(paths) | SourceInput::Directories(paths) => paths.clone(),
+            _ => vec![],
+        }
+    }
+
+    pub fn is_recursive(&self) -> bool {
+        matches!(self.input, SourceInput::Directories(_))
+    }
+
+    /// Takes the stream out of a `Stream` input, to be read with `read_lines_in_batches`.
+    /// Leaves an empty input behind.
+    pub fn take_stream(&mut self) -> Option<File> {
+        match std::mem::replace(&mut self.input, SourceInput::FullInput(vec![])) {
+            SourceInput::Stream(stream) => Some(stream),
+            input => {
+                self.input = input;
+                None
+            }
+        }
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self.input, SourceInput::Stream(_))
+    }
+
+    /// Whether reading the input again can give a different result.
+    pub fn is_reloadable(&self) -> bool {
+        !matches!(
+            self.input,
+            SourceInput::FullInput(_) | SourceInput::Stream(_)
+        )
+    }
+
+    /// Opens a `FilePath` input for incremental reading.
+    pub fn follow(&self) -> Result<FileFollower> {
+        match &self.input {
+            SourceInput::FilePath(path) => {
+                let mut magic = vec![];
+                File::open(path)
+                    .and_then(|f| f.take(MAX_MAGIC_LENGTH as u64).read_to_end(&mut magic))
+                    .with_context(|| format!("Failed to open file '{}'", path))?;
+                if detect_compression(&magic).is_some() {
+                    anyhow::bail!("Compressed files can't be followed");
+                }
+                if !self.decoder.encoding().is_ascii_compatible() {
+                    anyhow::bail!(
+                        "Files encoded as {} can't be followed",
+                        self.decoder.encoding()
+                    );
+                }
+                FileFollower::open(path.as_str(), self.decoder.clone())
+                    .with_context(|| format!("Failed to open file '{}'", path))
+            }
+            _ => anyhow::bail!("Only files can be followed"),
         }
     }
 }
 
-fn read_source_from_file(file_path: &str) -> io::Result<Vec<String>> {
+/// Changes to a followed file since it was last read.
+#[derive(Debug, PartialEq)]
+pub enum FollowUpdate {
+    /// Lines were appended to the file, or nothing changed.
+    Appended(Vec<String>),
+    /// The file was truncated, e.g. by `logrotate` with `copytruncate`.
+    /// Holds the full content of the file.
+    Truncated(Vec<String>),
+    /// The path refers to a new file, e.g. after the old one was renamed or
+    /// deleted and then recreated. Holds the rest of the old file, followed
+    /// by the full content of the new file, like `tail -F` shows them.
+    Rotated(Vec<String>),
+}
+
+/// Reads a file incrementally, remembering how far it has been read,
+/// so that only appended bytes are read when it changes.
+#[derive(Debug)]
+pub struct FileFollower {
+    path: String,
+    reader: BufReader<File>,
+    /// Byte offset after the last complete line read.
+    offset: u64,
+    /// Length of the file when it was last read, to notice it shrinking.
+    len: u64,
+    /// Device and inode of the opened file, used to detect rotation.
+    identity: (u64, u64),
+    decoder: Decoder,
+}
+
+impl FileFollower {
+    /// Opens a file to follow. The decoder's encoding has to be ASCII compatible.
+    pub fn open(path: &str, decoder: Decoder) -> io::Result<FileFollower> {
+        let file = File::open(path)?;
+        let identity = file_identity(&file.metadata()?);
+        Ok(FileFollower {
+            path: path.to_string(),
+            reader: BufReader::new(file),
+            offset: 0,
+            len: 0,
+            identity,
+            decoder,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Reads what has changed since the last call.
+    /// The first call returns the full content as `Appended`.
+    pub fn read_update(&mut self) -> io::Result<FollowUpdate> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) if file_identity(&metadata) != self.identity => {
+                // Lines written to the old file just before it was rotated
+                let mut lines = self.read_lines(true)?;
+                *self = FileFollower::open(&self.path, self.decoder.clone())?;
+                lines.extend(self.read_lines(false)?);
+                return Ok(FollowUpdate::Rotated(lines));
+            }
+            // Renamed or deleted, but not yet recreated. Keep reading the old file.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+            Ok(_) => {}
+        }
+        if self.is_truncated()? {
+            self.offset = 0;
+            self.decoder.reset_decoded_lossily();
+            return Ok(FollowUpdate::Truncated(self.read_lines(false)?));
+        }
+        Ok(FollowUpdate::Appended(self.read_lines(false)?))
+    }
+
+    /// Whether the file was truncated since it was last read: it is shorter
+    /// than it was, or it was written again past where it was truncated, so
+    /// the last line read no longer ends where it did.
+    fn is_truncated(&mut self) -> io::Result<bool> {
+        let len = self.reader.get_ref().metadata()?.len();
+        if len < self.len {
+            return Ok(true);
+        }
+        if self.offset == 0 {
+            return Ok(false);
+        }
+        let mut last_byte = [0u8];
+        self.reader.seek(SeekFrom::Start(self.offset - 1))?;
+        self.reader.read_exact(&mut last_byte)?;
+        Ok(last_byte[0] != self.decoder.line_terminator())
+    }
+
+    /// Reads the file again from the start, e.g. when asked to reload it.
+    /// Following continues after the returned lines, so none are read twice.
+    pub fn reload(&mut self) -> io::Result<Vec<String>> {
+        match FileFollower::open(&self.path, self.decoder.clone()) {
+            Ok(follower) => *self = follower,
+            // Renamed or deleted, but not yet recreated. Read the old file again.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.offset = 0,
+            Err(e) => return Err(e),
+        }
+        self.decoder.reset_decoded_lossily();
+        self.read_lines(false)
+    }
+
+    /// Reads all complete lines after `offset`. An unterminated last line is
+    /// left for a later read, since it may still be in the middle of being
+    /// written, unless `to_end` is set.
+    fn read_lines(&mut self, to_end: bool) -> io::Result<Vec<String>> {
+        self.reader.seek(SeekFrom::Start(self.offset))?;
+        let mut lines: Vec<String> = vec![];
+        let mut line: Vec<u8> = vec![];
+        loop {
+            line.clear();
+            let bytes_read = self
+                .reader
+                .read_until(self.decoder.line_terminator(), &mut line)?;
+            let terminated = line.last() == Some(&self.decoder.line_terminator());
+            if bytes_read == 0 || !(terminated || to_end) {
+                break;
+            }
+            self.decoder.trim_line_terminator(&mut line);
+            self.offset += bytes_read as u64;
+            lines.push(self.decoder.decode_line(std::mem::take(&mut line)));
+        }
+        self.len = self.reader.get_ref().metadata()?.len();
+        Ok(lines)
+    }
+}
+
+fn file_identity(metadata: &Metadata) -> (u64, u64) {
+    (metadata.dev(), metadata.ino())
+}
+
+/// Returns the paths of all files in the directory trees, in a stable order.
+/// Respects `.gitignore` and similar files, and skips hidden files.
+fn walk_directories(paths: &[String]) -> Result<Vec<String>> {
+    for path in paths {
+        fs::metadata(path).with_context(|| format!("Failed to open directory '{}'", path))?;
+    }
+    let mut builder = WalkBuilder::new(&paths[0]);
+    for path in &paths[1..] {
+        builder.add(path);
+    }
+    builder.sort_by_file_name(|a, b| a.cmp(b));
+    Ok(builder
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Reads a file, unless it is a binary file. Like ripgrep, a file is
+/// considered binary if there is a NUL byte in the beginning of it.
+fn read_text_file(file_path: &str, decoder: &Decoder) -> io::Result<Option<Vec<String>>> {
+    let mut reader = BufReader::new(decoder.reader(decompress(File::open(file_path)?)?)?);
+    if !decoder.is_null_data() && reader.fill_buf()?.contains(&0) {
+        return Ok(None);
+    }
+    read_lines(reader, decoder).map(Some)
+}
+
+fn read_source_from_file(file_path: &str, decoder: &Decoder) -> io::Result<Vec<String>> {
     let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
-    let source: Vec<String> = reader.lines().map_while(Result::ok).collect();
-    Ok(source)
+    let reader = BufReader::new(decoder.reader(decompress(file)?)?);
+    read_lines(reader, decoder)
 }
 
-pub fn read_source_from_stdin() -> Vec<String> {
-    let stdin = io::stdin();
-    stdin.lock().lines().map_while(Result::ok).collect()
+fn read_lines<R: Read>(reader: BufReader<R>, decoder: &Decoder) -> io::Result<Vec<String>> {
+    let mut lines: Vec<String> = vec![];
+    read_lines_in_batches(reader, decoder, |batch| lines.extend(batch))?;
+    Ok(lines)
+}
+
+enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+fn detect_compression(magic: &[u8]) -> Option<Compression> {
+    if magic.starts_with(GZIP_MAGIC) {
+        Some(Compression::Gzip)
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Some(Compression::Zstd)
+    } else if magic.starts_with(BZIP2_MAGIC) {
+        Some(Compression::Bzip2)
+    } else if magic.starts_with(XZ_MAGIC) {
+        Some(Compression::Xz)
+    } else {
+        None
+    }
+}
+
+/// Wraps a reader so that gzip, zstd, bzip2 and xz compressed data is
+/// decompressed on the fly. The format is detected by its magic bytes,
+/// other data is read as is.
+pub fn decompress<R: Read + Send + 'static>(mut reader: R) -> io::Result<Box<dyn Read + Send>> {
+    let mut magic = Vec::with_capacity(MAX_MAGIC_LENGTH);
+    (&mut reader)
+        .take(MAX_MAGIC_LENGTH as u64)
+        .read_to_end(&mut magic)?;
+    let compression = detect_compression(&magic);
+    let reader = io::Cursor::new(magic).chain(reader);
+    Ok(match compression {
+        Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(reader)),
+        Some(Compression::Zstd) => Box::new(zstd::Decoder::new(reader)?),
+        Some(Compression::Bzip2) => Box::new(MultiBzDecoder::new(reader)),
+        Some(Compression::Xz) => Box::new(XzDecoder::new_multi_decoder(reader)),
+        None => Box::new(reader),
+    })
+}
+
+/// Reads lines until EOF, passing them on in batches. On a read error the
+/// lines read so far are passed on before the error is returned.
+/// The reader is expected to produce UTF-8, see `Decoder::reader`.
+/// A batch is passed on whenever the next read might block, so slow
+/// input such as `tail -f` shows up line by line.
+pub fn read_lines_in_batches<R: Read, F: FnMut(Vec<String>)>(
+    mut reader: BufReader<R>,
+    decoder: &Decoder,
+    mut on_batch: F,
+) -> io::Result<()> {
+    let mut batch: Vec<String> = vec![];
+    let mut line: Vec<u8> = vec![];
+    let result = loop {
+        match reader.read_until(decoder.line_terminator(), &mut line) {
+            Ok(0) => break Ok(()),
+            Ok(_) => {
+                decoder.trim_line_terminator(&mut line);
+                batch.push(decoder.decode_utf8(std::mem::take(&mut line)));
+                if reader.buffer().is_empty() || batch.len() >= MAX_BATCH_SIZE {
+                    on_batch(std::mem::take(&mut batch));
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    if !batch.is_empty() {
+        on_batch(batch);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    /// A directory of its own for each test, removed when the test ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path =
+                std::env::temp_dir().join(format!("igrepper-test-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn join(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn append(path: &PathBuf, content: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    fn lines(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn read_decompressed(data: Vec<u8>) -> String {
+        let mut output = String::new();
+        decompress(io::Cursor::new(data))
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn decompress_detected_formats() {
+        let input = "compressed\nlines\n".as_bytes();
+
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(input).unwrap();
+        assert_eq!(
+            read_decompressed(gzip.finish().unwrap()),
+            "compressed\nlines\n"
+        );
+
+        let zstd = zstd::encode_all(input, 0).unwrap();
+        assert_eq!(read_decompressed(zstd), "compressed\nlines\n");
+
+        let mut bzip2 = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+        bzip2.write_all(input).unwrap();
+        assert_eq!(
+            read_decompressed(bzip2.finish().unwrap()),
+            "compressed\nlines\n"
+        );
+
+        let mut xz = xz2::write::XzEncoder::new(vec![], 6);
+        xz.write_all(input).unwrap();
+        assert_eq!(
+            read_decompressed(xz.finish().unwrap()),
+            "compressed\nlines\n"
+        );
+
+        assert_eq!(read_decompressed(b"BZ\n".to_vec()), "BZ\n");
+        assert_eq!(read_decompressed(vec![]), "");
+    }
+
+    #[test]
+    fn read_past_invalid_utf8() {
+        let directory = TempDir::new("invalid_utf8");
+        let path = directory.join("file");
+        fs::write(&path, b"one\nt\xffo\r\nthree").unwrap();
+        let decoder = Decoder::default();
+        assert_eq!(
+            read_source_from_file(path.to_str().unwrap(), &decoder).unwrap(),
+            lines(&["one", "t\u{fffd}o", "three"])
+        );
+        assert!(decoder.decoded_lossily());
+    }
+
+    #[test]
+    fn report_read_errors() {
+        let corrupt = GZIP_MAGIC
+            .iter()
+            .chain(b"not gzip")
+            .copied()
+            .collect::<Vec<u8>>();
+        let reader = decompress(io::Cursor::new(corrupt)).unwrap();
+        let mut batches = vec![];
+        let result = read_lines_in_batches(BufReader::new(reader), &Decoder::default(), |batch| {
+            batches.push(batch)
+        });
+        assert!(result.is_err());
+        assert!(batches.is_empty());
+
+        let failing = io::Cursor::new(b"one\ntwo".to_vec()).chain(FailingReader);
+        let mut batches = vec![];
+        let result = read_lines_in_batches(BufReader::new(failing), &Decoder::default(), |batch| {
+            batches.push(batch)
+        });
+        assert!(result.is_err());
+        assert_eq!(batches.concat(), lines(&["one"]));
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("failed"))
+        }
+    }
+
+    #[test]
+    fn map_only_large_files() {
+        let directory = TempDir::new("mapped");
+        let path = directory.join("file");
+        fs::write(&path, "one\n").unwrap();
+        let source_producer = SourceProducer {
+            input: SourceInput::FilePath(path.to_str().unwrap().to_string()),
+            decoder: Decoder::default(),
+        };
+        assert!(source_producer.map_file().unwrap().is_none());
+
+        // Sparse, so it takes no space
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(MIN_MAPPED_FILE_SIZE)
+            .unwrap();
+        assert!(source_producer.map_file().unwrap().is_some());
+    }
+
+    #[test]
+    fn read_null_data() {
+        let directory = TempDir::new("null_data");
+        let path = directory.join("file");
+        fs::write(&path, b"one\r\ntwo\0\0three\0").unwrap();
+        let decoder = Decoder::default().with_null_data();
+        assert_eq!(
+            read_text_file(path.to_str().unwrap(), &decoder).unwrap(),
+            Some(lines(&["one\r\ntwo", "", "three"]))
+        );
+    }
+
+    #[test]
+    fn run_command() {
+        let source_producer = SourceProducer {
+            input: SourceInput::Command(String::from("echo out; echo err >&2; exit 3")),
+            decoder: Decoder::default(),
+        };
+        let output = source_producer.run_command().unwrap().unwrap();
+        assert_eq!(output.stdout, lines(&["out"]));
+        assert_eq!(output.stderr, lines(&["err"]));
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[test]
+    fn read_directories_recursively() {
+        let temp_dir = TempDir::new("recursive");
+        let directory = temp_dir.join("input");
+        fs::create_dir_all(directory.join("sub")).unwrap();
+        append(&directory.join("b.txt"), "b\n");
+        append(&directory.join("sub/a.txt"), "a\n");
+        append(&directory.join("binary"), "x\0y\n");
+        append(&directory.join(".hidden"), "hidden\n");
+        append(&directory.join("skipped.log"), "skipped\n");
+        append(&directory.join(".ignore"), "*.log\n");
+        let directory = directory.to_str().unwrap().to_string();
+        let source_producer = SourceProducer {
+            input: SourceInput::Directories(vec![directory.clone()]),
+            decoder: Decoder::default(),
+        };
+        assert_eq!(
+            source_producer.get_source_files().unwrap(),
+            vec![
+                (format!("{}/b.txt", directory), lines(&["b"])),
+                (format!("{}/sub/a.txt", directory), lines(&["a"])),
+            ]
+        );
+    }
+
+    #[test]
+    fn follow_appended_lines() {
+        let directory = TempDir::new("appended");
+        let path = directory.join("file");
+        append(&path, "one\ntwo\n");
+        let mut follower = FileFollower::open(path.to_str().unwrap(), Decoder::default()).unwrap();
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(lines(&["one", "two"]))
+        );
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(vec![])
+        );
+        append(&path, "three\r\nfo");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(lines(&["three"]))
+        );
+        append(&path, "ur\n");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(lines(&["four"]))
+        );
+    }
+
+    #[test]
+    fn follow_after_reload() {
+        let directory = TempDir::new("reloaded");
+        let path = directory.join("file");
+        append(&path, "one\n");
+        let mut follower = FileFollower::open(path.to_str().unwrap(), Decoder::default()).unwrap();
+        follower.read_update().unwrap();
+        append(&path, "two\n");
+        assert_eq!(follower.reload().unwrap(), lines(&["one", "two"]));
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(vec![])
+        );
+        append(&path, "three\n");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(lines(&["three"]))
+        );
+    }
+
+    #[test]
+    fn reload_forgets_invalid_input() {
+        let directory = TempDir::new("reloaded_invalid");
+        let path = directory.join("file");
+        fs::write(&path, b"t\xffo\n").unwrap();
+        let decoder = Decoder::default();
+        let mut follower = FileFollower::open(path.to_str().unwrap(), decoder.clone()).unwrap();
+        follower.read_update().unwrap();
+        assert!(decoder.decoded_lossily());
+        fs::write(&path, b"two\n").unwrap();
+        assert_eq!(follower.reload().unwrap(), lines(&["two"]));
+        assert!(!decoder.decoded_lossily());
+    }
+
+    #[test]
+    fn follow_truncated_file() {
+        let directory = TempDir::new("truncated");
+        let path = directory.join("file");
+        append(&path, "one\ntwo\n");
+        let mut follower = FileFollower::open(path.to_str().unwrap(), Decoder::default()).unwrap();
+        follower.read_update().unwrap();
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        append(&path, "new\n");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Truncated(lines(&["new"]))
+        );
+    }
+
+    #[test]
+    fn follow_file_truncated_and_written_past_offset() {
+        let directory = TempDir::new("truncated_longer");
+        let path = directory.join("file");
+        append(&path, "one\ntwo\n");
+        let mut follower = FileFollower::open(path.to_str().unwrap(), Decoder::default()).unwrap();
+        follower.read_update().unwrap();
+        fs::write(&path, "a longer first line\n").unwrap();
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Truncated(lines(&["a longer first line"]))
+        );
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Truncated(vec![])
+        );
+        append(&path, "new\n");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(lines(&["new"]))
+        );
+    }
+
+    #[test]
+    fn follow_rotated_file() {
+        let directory = TempDir::new("rotated");
+        let path = directory.join("file");
+        let rotated_path = directory.join("file.1");
+        append(&path, "one\n");
+        let mut follower = FileFollower::open(path.to_str().unwrap(), Decoder::default()).unwrap();
+        follower.read_update().unwrap();
+        fs::rename(&path, &rotated_path).unwrap();
+        append(&rotated_path, "two\n");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(lines(&["two"]))
+        );
+        append(&rotated_path, "last\nunterminated");
+        append(&path, "three\n");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Rotated(lines(&["last", "unterminated", "three"]))
+        );
+        append(&path, "four\n");
+        assert_eq!(
+            follower.read_update().unwrap(),
+            FollowUpdate::Appended(lines(&["four"]))
+        );
+    }
 }
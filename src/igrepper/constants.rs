@@ -1,4 +1,19 @@
 pub const CASE_INSENSITIVE_PREFIX: &str = "(?i)";
+/// Shown in front of search lines that are matched verbatim.
+pub const LITERAL_PREFIX: &str = "(lit)";
+/// Shown in front of search lines in smartcase mode.
+pub const SMART_CASE_PREFIX: &str = "(smart)";
+/// Shown in front of search lines that may use lookaround and backreferences.
+pub const FANCY_ENGINE_PREFIX: &str = "(fancy)";
+/// Shown in front of fuzzy search lines, in source order and ranked.
+pub const FUZZY_PREFIX: &str = "(fuzzy)";
+pub const FUZZY_RANKED_PREFIX: &str = "(fuzzy, ranked)";
+/// Shown in front of search lines made up of AND, NOT and OR terms.
+pub const QUERY_PREFIX: &str = "(terms)";
+/// Shown in front of the earlier branches of a union, their search lines
+/// separated by `BRANCH_SEPARATOR`.
+pub const BRANCH_PREFIX: &str = "(branch) ";
+pub const BRANCH_SEPARATOR: &str = " > ";
 
 pub static COLOR_PAIR_DEFAULT: i16 = 128;
 pub static COLOR_PAIR_INACTIVE_INPUT: i16 = 129;
@@ -26,18 +41,43 @@ pub const MATCH_COLORS: [i16; 18] = [
     111, 214, 129, 226, 215, 70,
 ];
 
+/// Colour families of the matches of earlier search lines, the one before
+/// the current search line using the first family. Their colour indices
+/// follow those of `MATCH_COLORS`.
+pub const STAGE_MATCH_COLOR_SHADES: usize = 3;
+pub const STAGE_MATCH_COLORS: [[i16; STAGE_MATCH_COLOR_SHADES]; 4] = [
+    [39, 75, 111],   // blues
+    [77, 114, 151],  // greens
+    [172, 209, 216], // oranges
+    [135, 171, 183], // purples
+];
+
+pub const CTRL_A: i32 = 'a' as i32 - 0x60;
+pub const CTRL_B: i32 = 'b' as i32 - 0x60;
 pub const CTRL_D: i32 = 'd' as i32 - 0x60;
 pub const CTRL_E: i32 = 'e' as i32 - 0x60;
+pub const CTRL_F: i32 = 'f' as i32 - 0x60;
 pub const CTRL_G: i32 = 'g' as i32 - 0x60;
 pub const CTRL_H: i32 = 'h' as i32 - 0x60;
 pub const CTRL_I: i32 = 'i' as i32 - 0x60;
+pub const CTRL_K: i32 = 'k' as i32 - 0x60;
 pub const CTRL_L: i32 = 'l' as i32 - 0x60;
 pub const CTRL_N: i32 = 'n' as i32 - 0x60;
+pub const CTRL_O: i32 = 'o' as i32 - 0x60;
 pub const CTRL_P: i32 = 'p' as i32 - 0x60;
 pub const CTRL_R: i32 = 'r' as i32 - 0x60;
+pub const CTRL_S: i32 = 's' as i32 - 0x60;
 pub const CTRL_T: i32 = 't' as i32 - 0x60;
 pub const CTRL_U: i32 = 'u' as i32 - 0x60;
 pub const CTRL_V: i32 = 'v' as i32 - 0x60;
+pub const CTRL_W: i32 = 'w' as i32 - 0x60;
+pub const CTRL_X: i32 = 'x' as i32 - 0x60;
+pub const CTRL_Y: i32 = 'y' as i32 - 0x60;
 pub const F1: i32 = 27;
 pub const F1_2: i32 = 265;
+pub const F2: i32 = 266;
+pub const F3: i32 = 267;
+pub const F5: i32 = 269;
+pub const F6: i32 = 270;
+pub const F7: i32 = 271;
 pub const ALTERNATIVE_BACKSPACE: i32 = 127;
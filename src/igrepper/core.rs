@@ -1,44 +1,76 @@
-use crate::igrepper::output_generator::{Len, OutputGenerator, SourceLines};
-use crate::igrepper::state::{SearchLine, State};
-use crate::igrepper::trimming::produce_render_state;
+use crate::igrepper::matcher::Matcher;
+use crate::igrepper::output_generator::{Len, OutputGenerator, RecordMode, SourceLines};
+use crate::igrepper::state::{Context, SearchLine, State};
+use crate::igrepper::trimming::{produce_render_state, MatchColors};
 use crate::igrepper::types::RenderState;
+use std::cmp;
 use std::collections::HashMap;
 
 #[derive(Debug)]
 struct CacheEntry {
     pub search_line: String,
     pub output_generator: OutputGenerator,
-    /// If this generator's source is buffered from a parent, stores the
-    /// parent's cache key so Core can drain matching lines before request().
-    pub parent_key: Option<CacheKey>,
+    /// If this generator's source is buffered from parents, stores the
+    /// parents' cache keys so Core can drain matching lines before request().
+    /// A stage has one parent, the stage merging the branches of a union has
+    /// one per branch.
+    pub parent_keys: Vec<CacheKey>,
+    /// The colours of the matches shown so far.
+    pub match_colors: MatchColors,
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
 struct CacheKey {
     search_lines: Vec<SearchLine>,
-    context: u32,
+    context: Context,
     inverted: bool,
     active_regex: String,
+    branches: Vec<Vec<SearchLine>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Core {
     cache: HashMap<CacheKey, CacheEntry>,
+    record_mode: RecordMode,
+    /// Whether stages after the first take context from their own input
+    /// instead of from the original input.
+    filtered_context: bool,
 }
 
+/// Number of additional output lines requested from a parent each time a
+/// child stage runs out of buffered lines.
+const PARENT_REQUEST_CHUNK_SIZE: u32 = 1000;
+
 fn get_cache_key(state: &State) -> CacheKey {
     CacheKey {
         search_lines: state.search_lines(),
         context: state.current_context(),
         inverted: state.inverted(),
         active_regex: state.last_valid_regex().to_string(),
+        branches: state.branches(),
     }
 }
 
 impl Core {
     pub fn new() -> Core {
+        Core::with_record_mode(RecordMode::Lines)
+    }
+
+    /// A Core that matches whole records instead of single lines.
+    pub fn with_record_mode(record_mode: RecordMode) -> Core {
         Core {
             cache: HashMap::new(),
+            record_mode,
+            filtered_context: false,
+        }
+    }
+
+    /// Makes the context of a stage its neighbouring lines in the output of
+    /// the stage before, like in a pipeline of grep commands.
+    pub fn with_filtered_context(self, filtered_context: bool) -> Core {
+        Core {
+            filtered_context,
+            ..self
         }
     }
 
@@ -47,19 +79,22 @@ impl Core {
     }
 
     pub fn get_full_output_string(&mut self, state: &State) -> String {
-        let key = get_cache_key(state);
-        self.populate_cache(state);
-        self.drain_and_request_full(&key);
+        self.get_full_output(state).join("\n")
+    }
+
+    /// All output lines, without separators.
+    pub fn get_full_output(&mut self, state: &State) -> Vec<String> {
+        let key = self.prepare(state);
+        self.drain_parent_into_child(&key, u32::MAX);
         self.cache
             .get_mut(&key)
             .unwrap()
             .output_generator
-            .full_string()
+            .full_string_vec(state.line_numbers())
     }
 
     pub fn widest_line_seen_so_far(&mut self, state: &State) -> u32 {
-        let key = get_cache_key(state);
-        self.populate_cache(state);
+        let key = self.prepare(state);
         self.cache
             .get(&key)
             .unwrap()
@@ -68,8 +103,7 @@ impl Core {
     }
 
     pub fn is_output_length_at_least(&mut self, state: &State, length: u32) -> u32 {
-        let key = get_cache_key(state);
-        self.populate_cache(state);
+        let key = self.prepare(state);
         self.drain_parent_into_child(&key, length);
         let output_generator = &mut self.cache.get_mut(&key).unwrap().output_generator;
         output_generator.request(length);
@@ -77,100 +111,130 @@ impl Core {
     }
 
     pub fn get_current_output_length(&mut self, state: &State) -> Len {
-        let key = get_cache_key(state);
-        self.populate_cache(state);
+        let key = self.prepare(state);
         self.cache.get(&key).unwrap().output_generator.len()
     }
 
     pub fn get_render_state(&mut self, state: &State) -> RenderState {
-        let key = get_cache_key(state);
-        self.populate_cache(state);
+        let key = self.prepare(state);
         let lines_needed = state.pager_y() + state.max_y() + 10;
         self.drain_parent_into_child(&key, lines_needed);
-        let output_generator = &mut self.cache.get_mut(&key).unwrap().output_generator;
+        let earlier_regexes = self.earlier_regexes(&key);
+        let entry = self.cache.get_mut(&key).unwrap();
         produce_render_state(
-            state.regex_valid(),
-            state.max_y(),
-            state.max_x(),
-            state.pager_y(),
-            state.pager_x(),
-            &state.search_lines(),
-            state.current_context(),
-            output_generator,
+            state,
+            &earlier_regexes,
+            &mut entry.match_colors,
+            &mut entry.output_generator,
         )
     }
 
-    /// Drains matching lines from a parent OutputGenerator into the child's
-    /// buffered source. Requests enough lines from the parent to cover the
-    /// child's needs, then copies new matching lines into the child's buffer.
-    fn drain_parent_into_child(&mut self, child_key: &CacheKey, child_requested: u32) {
-        let entry = self.cache.get(child_key).unwrap();
-        let parent_key = match &entry.parent_key {
-            Some(k) => k.clone(),
-            None => return,
-        };
-
-        let child_needs = {
-            let child = &entry.output_generator;
-            let request_chunk_size: u32 = 1000;
-            let end = child_requested
-                .saturating_sub(child_requested % request_chunk_size)
-                .saturating_add(request_chunk_size);
-            end.saturating_add(child.context()).saturating_add(1) as usize
-        };
-        let already_buffered = entry.output_generator.source_buffered_len();
-
-        if already_buffered >= child_needs {
-            return;
+    /// The regexes of the stages before the one at `key`, the closest first.
+    /// The stage merging a union has no single stage before it.
+    fn earlier_regexes(&self, key: &CacheKey) -> Vec<Matcher> {
+        let mut regexes = vec![];
+        let mut parent_keys = &self.cache[key].parent_keys;
+        while let [parent_key] = parent_keys.as_slice() {
+            let parent = &self.cache[parent_key];
+            // Lines shown by an inverted stage have nothing it matched
+            if !parent.output_generator.inverted() {
+                regexes.push(parent.output_generator.regex().clone());
+            }
+            parent_keys = &parent.parent_keys;
         }
+        regexes
+    }
 
-        // First, recursively ensure the parent is also drained from its parent.
-        self.drain_parent_into_child(&parent_key, child_needs as u32);
-
-        let parent = &mut self.cache.get_mut(&parent_key).unwrap().output_generator;
-        parent.request(child_needs as u32);
-        let parent_matching_count = parent.matching_line_count();
-        let parent_exhausted = parent.is_fully_processed();
-        let new_lines: Vec<String> = if already_buffered < parent_matching_count {
-            parent.matching_lines()[already_buffered..parent_matching_count].to_vec()
-        } else {
-            vec![]
-        };
-
-        let child = &mut self.cache.get_mut(child_key).unwrap().output_generator;
-        child
-            .source_lines_mut()
-            .extend_buffer(&new_lines, parent_exhausted);
+    /// Populates the cache for `state`, and shows its output the way the
+    /// state asks for. Returns the key of the output generator to use.
+    fn prepare(&mut self, state: &State) -> CacheKey {
+        let key = get_cache_key(state);
+        self.populate_cache(state);
+        self.cache
+            .get_mut(&key)
+            .unwrap()
+            .output_generator
+            .set_display_mode(state.display_mode());
+        key
     }
 
-    /// Fully drains parent into child (used for full_string/full_vec).
-    fn drain_and_request_full(&mut self, child_key: &CacheKey) {
-        let entry = self.cache.get(child_key).unwrap();
-        if entry.parent_key.is_none() {
+    /// Drains matching lines from the parent OutputGenerators into the child's
+    /// buffered source. Keeps requesting more lines from the parents until the
+    /// child has produced the requested output, or the parents have no more lines.
+    /// Lines from several parents are merged in their original order.
+    fn drain_parent_into_child(&mut self, child_key: &CacheKey, child_requested: u32) {
+        let parent_keys = self.cache.get(child_key).unwrap().parent_keys.clone();
+        if parent_keys.is_empty() {
             return;
         }
-        let parent_key = entry.parent_key.clone().unwrap();
-        let already_buffered = entry.output_generator.source_buffered_len();
 
-        self.drain_and_request_full(&parent_key);
+        loop {
+            let child = &mut self.cache.get_mut(child_key).unwrap().output_generator;
+            child.request(child_requested);
+            if child.len_simple() >= child_requested || !child.is_waiting_for_source() {
+                return;
+            }
+            let last_buffered = child.last_buffered_source_index();
 
-        let parent = &mut self.cache.get_mut(&parent_key).unwrap().output_generator;
-        parent.request(u32::MAX);
-        let parent_matching_count = parent.matching_line_count();
-        let parent_exhausted = parent.is_fully_processed();
-        let new_lines: Vec<String> = if already_buffered < parent_matching_count {
-            parent.matching_lines()[already_buffered..parent_matching_count].to_vec()
-        } else {
-            vec![]
-        };
+            let mut new_lines: Vec<usize> = vec![];
+            let mut line_contexts: Vec<(usize, Context)> = vec![];
+            let mut parents_exhausted = true;
+            // Parents that are not done yet may still add lines from here on
+            let mut unprocessed_start = usize::MAX;
+            for parent_key in &parent_keys {
+                let parent_requested = self
+                    .cache
+                    .get(parent_key)
+                    .unwrap()
+                    .output_generator
+                    .len_simple()
+                    .saturating_add(PARENT_REQUEST_CHUNK_SIZE);
+                // First, recursively ensure the parent is also drained from its parents.
+                self.drain_parent_into_child(parent_key, parent_requested);
+
+                let parent = &mut self.cache.get_mut(parent_key).unwrap().output_generator;
+                parent.request(parent_requested);
+                let matching_lines = parent.matching_lines();
+                let first_new = match last_buffered {
+                    Some(last) => matching_lines.partition_point(|&i| i <= last),
+                    None => 0,
+                };
+                let parent_new_lines = &matching_lines[first_new..];
+                if parent_keys.len() > 1 {
+                    // The branches of a union each keep their own context
+                    let context = parent.context();
+                    line_contexts.extend(parent_new_lines.iter().map(|&i| (i, context)));
+                }
+                new_lines.extend_from_slice(parent_new_lines);
+                if !parent.is_fully_processed() {
+                    parents_exhausted = false;
+                    unprocessed_start =
+                        cmp::min(unprocessed_start, parent.processed_source_line_count());
+                }
+            }
+            new_lines.retain(|&i| i < unprocessed_start);
+            new_lines.sort_unstable();
+            new_lines.dedup();
+            if new_lines.is_empty() && !parents_exhausted {
+                // The parents are waiting for more input
+                return;
+            }
 
-        let child = &mut self.cache.get_mut(child_key).unwrap().output_generator;
-        child
-            .source_lines_mut()
-            .extend_buffer(&new_lines, parent_exhausted);
+            let child = &mut self.cache.get_mut(child_key).unwrap().output_generator;
+            for (source_index, context) in line_contexts {
+                child.add_line_context(source_index, context);
+            }
+            child
+                .source_lines_mut()
+                .extend_buffer(&new_lines, parents_exhausted);
+        }
     }
 
     fn populate_cache(&mut self, state: &State) {
+        if !state.branches().is_empty() {
+            self.populate_union_cache(state);
+            return;
+        }
         let first_line = state.search_line_strings().len() == 1;
         if !first_line {
             self.populate_cache(&state.clone().revert_partial_match());
@@ -190,11 +254,14 @@ impl Core {
             cache_ok = false;
         }
         if !cache_ok {
-            let (source_lines, parent_key) = if first_line {
-                (SourceLines::Raw(state.source_lines_arc()), None)
+            let (source_lines, parent_keys) = if first_line {
+                (SourceLines::Raw(state.source()), vec![])
             } else {
                 let reverted_key = get_cache_key(&state.clone().revert_partial_match());
-                (SourceLines::new_buffered(), Some(reverted_key))
+                (
+                    SourceLines::new_buffered(state.source()),
+                    vec![reverted_key],
+                )
             };
             let output_generator = OutputGenerator::new(
                 source_lines,
@@ -202,24 +269,75 @@ impl Core {
                 state.last_search_line_empty(),
                 state.current_context(),
                 state.inverted(),
+                self.record_mode.clone(),
+                self.filtered_context,
             );
             self.cache.insert(
                 get_cache_key(state),
                 CacheEntry {
                     search_line: s,
                     output_generator,
-                    parent_key,
+                    parent_keys,
+                    match_colors: MatchColors::default(),
                 },
             );
         }
     }
+
+    /// Adds the branches of a union, and the stage merging their output.
+    /// Matches of each branch's last search line are highlighted.
+    fn populate_union_cache(&mut self, state: &State) {
+        let branch_states = state.branch_states();
+        for branch_state in &branch_states {
+            self.populate_cache(branch_state);
+        }
+        let key = get_cache_key(state);
+        if self.cache.contains_key(&key) {
+            return;
+        }
+        let regex = Matcher::union(
+            branch_states
+                .iter()
+                .filter(|s| !s.inverted())
+                .map(|s| s.last_valid_regex())
+                .collect(),
+        );
+        // Lines take the context of their branch, see `drain_parent_into_child`
+        let context = branch_states
+            .iter()
+            .map(|s| s.current_context())
+            .fold(Context::default(), Context::widest);
+        let output_generator = OutputGenerator::new(
+            SourceLines::new_buffered(state.source()),
+            regex,
+            false,
+            context,
+            false,
+            self.record_mode.clone(),
+            self.filtered_context,
+        );
+        self.cache.insert(
+            key,
+            CacheEntry {
+                search_line: state.search_line_strings().last().unwrap().clone(),
+                output_generator,
+                parent_keys: branch_states.iter().map(get_cache_key).collect(),
+                match_colors: MatchColors::default(),
+            },
+        );
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::igrepper::matcher::Engine;
+    use crate::igrepper::output_generator::DisplayMode;
+    use crate::igrepper::source::Source;
     use crate::igrepper::state::SearchLine;
     use std::collections::HashSet;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
 
     use std::fs::File;
     use std::io::{Read, Write};
@@ -244,7 +362,7 @@ mod tests {
         );
         let output = core.get_render_state(&state);
         let serialized = format!("{:?}", output);
-        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 3, pager_window_height: 6, output_search_lines: [SearchLine { line: \"\", context: 0, case_sensitive: true, inverse: false }], output_display_lines: [StringWithColorIndex([String(\"b\"), String(\"l\"), String(\"a\"), String(\"h\")])], status_line: \"matchedLin\" }");
+        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 3, pager_window_height: 6, output_branch_lines: [], output_search_lines: [SearchLine { line: \"\", context: Context { before: 0, after: 0 }, case_mode: Sensitive, inverse: false, literal: false, engine: Standard, query: false }], output_display_lines: [StringWithColorIndex([String(\"b\"), String(\"l\"), String(\"a\"), String(\"h\")])], status_line: \"matchedLin\" }");
     }
 
     #[test]
@@ -266,7 +384,176 @@ mod tests {
         );
         let output = core.get_render_state(&state);
         let serialized = format!("{:?}", output);
-        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 3, pager_window_height: 6, output_search_lines: [SearchLine { line: \"1\", context: 1, case_sensitive: true, inverse: false }], output_display_lines: [StringWithColorIndex([MatchString((\"1\", 0))]), StringWithColorIndex([String(\"2\")])], status_line: \"matchedLin\" }");
+        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 3, pager_window_height: 6, output_branch_lines: [], output_search_lines: [SearchLine { line: \"1\", context: Context { before: 1, after: 1 }, case_mode: Sensitive, inverse: false, literal: false, engine: Standard, query: false }], output_display_lines: [StringWithColorIndex([MatchString((\"1\", 0))]), StringWithColorIndex([String(\"2\")])], status_line: \"matchedLin\" }");
+    }
+
+    #[test]
+    fn test_inverted_context() {
+        let source_lines: Vec<String> = ["a", "hb", "b", "hb", "hb", "hb", "c"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut core = Core::new();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(String::from("hb"), 1, true, true)],
+            0,
+            0,
+            20,
+            10,
+        );
+        assert_eq!(core.get_full_output_string(&state), "a\nhb\nb\nhb\nhb\nc");
+        let output = core.get_render_state(&state);
+        assert_eq!(format!("{:?}", output.output_display_lines), "[StringWithColorIndex([String(\"a\")]), StringWithColorIndex([String(\"hb\")]), StringWithColorIndex([String(\"b\")]), StringWithColorIndex([String(\"hb\")]), BreakLine, StringWithColorIndex([String(\"hb\")]), StringWithColorIndex([String(\"c\")])]");
+    }
+
+    #[test]
+    fn test_sub_stage_context() {
+        let source_lines: Vec<String> = ["a 1", "b", "a 2", "c", "d", "a 3"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let state = State::new(
+            source_lines,
+            vec![
+                SearchLine::new(String::from("a"), 0, true, false),
+                SearchLine::new(String::from("2"), 1, true, false),
+            ],
+            0,
+            0,
+            10,
+            10,
+        );
+        // The neighbours in the input, not the other lines matching "a"
+        assert_eq!(Core::new().get_full_output_string(&state), "b\na 2\nc");
+        assert_eq!(
+            Core::new()
+                .with_filtered_context(true)
+                .get_full_output_string(&state),
+            "a 1\na 2\na 3"
+        );
+    }
+
+    #[test]
+    fn test_asymmetric_context() {
+        let source_lines: Vec<String> = (1..=6).map(|i| i.to_string()).collect();
+        let mut core = Core::new();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine {
+                context: Context::new(1, 2),
+                ..SearchLine::new(String::from("3"), 0, true, false)
+            }],
+            0,
+            0,
+            10,
+            80,
+        );
+        assert_eq!(core.get_full_output_string(&state), "2\n3\n4\n5");
+        assert_eq!(
+            core.get_render_state(&state).status_line,
+            "matchedLines=5 pageY: 0, pageX: 0, context: 1 before, 2 after"
+        );
+    }
+
+    #[test]
+    fn test_color_modes() {
+        let source_lines = vec![String::from("GET /a"), String::from("x POST")];
+        let mut core = Core::new();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(
+                String::from("(GET) (/a)|POST"),
+                0,
+                true,
+                false,
+            )],
+            0,
+            0,
+            10,
+            80,
+        );
+        let display_lines = |core: &mut Core, state: &State| {
+            format!("{:?}", core.get_render_state(state).output_display_lines)
+        };
+        assert_eq!(display_lines(&mut core, &state), "[StringWithColorIndex([MatchString((\"GET /a\", 0))]), StringWithColorIndex([String(\"x \"), MatchString((\"POST\", 1))])]");
+        let state = state.cycle_color_mode();
+        assert_eq!(display_lines(&mut core, &state), "[StringWithColorIndex([MatchString((\"GET\", 1)), MatchString((\" \", 0)), MatchString((\"/a\", 2))]), StringWithColorIndex([String(\"x \"), MatchString((\"POST\", 0))])]");
+        let state = state.cycle_color_mode();
+        assert_eq!(display_lines(&mut core, &state), "[StringWithColorIndex([MatchString((\"GET /a\", 0))]), StringWithColorIndex([String(\"x \"), MatchString((\"POST\", 1))])]");
+        assert_eq!(
+            core.get_render_state(&state).status_line,
+            "matchedLines=2 pageY: 0, pageX: 0, context: 0, colors by branch"
+        );
+    }
+
+    #[test]
+    fn test_earlier_matches_on_matching_lines() {
+        let source_lines: Vec<String> = ["a 1", "b a", "a 2"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut core = Core::new();
+        let state = State::new(
+            source_lines.clone(),
+            vec![
+                SearchLine::new(String::from("a"), 0, true, false),
+                SearchLine::new(String::from("2"), 1, true, false),
+            ],
+            0,
+            0,
+            10,
+            80,
+        );
+        // Not on the context line, although "a" matched it too
+        assert_eq!(
+            format!("{:?}", core.get_render_state(&state).output_display_lines),
+            "[BreakLine, StringWithColorIndex([String(\"b a\")]), StringWithColorIndex([MatchString((\"a\", 18)), String(\" \"), MatchString((\"2\", 0))])]"
+        );
+
+        // An inverted stage has no matches, and takes no colours of its own
+        let state = State::new(
+            source_lines,
+            vec![
+                SearchLine::new(String::from("a"), 0, true, false),
+                SearchLine::new(String::from("b"), 0, true, true),
+                SearchLine::new(String::from("2"), 0, true, false),
+            ],
+            0,
+            0,
+            10,
+            80,
+        );
+        assert_eq!(
+            format!("{:?}", core.get_render_state(&state).output_display_lines),
+            "[StringWithColorIndex([MatchString((\"a\", 18)), String(\" \"), MatchString((\"2\", 0))])]"
+        );
+    }
+
+    #[test]
+    fn test_colors_stay_when_scrolling() {
+        let source_lines: Vec<String> = (1..=9).map(|i| format!("x{}", i)).collect();
+        let mut core = Core::new();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(String::from("x\\d"), 0, true, false)],
+            0,
+            0,
+            7,
+            80,
+        );
+        let first_line = |core: &mut Core, state: &State| {
+            format!("{:?}", core.get_render_state(state).output_display_lines[0])
+        };
+        assert_eq!(
+            first_line(&mut core, &state),
+            "StringWithColorIndex([MatchString((\"x1\", 0))])"
+        );
+        let state = state.page_y(2, 9);
+        assert_eq!(
+            first_line(&mut core, &state),
+            "StringWithColorIndex([MatchString((\"x3\", 2))])"
+        );
     }
 
     #[test]
@@ -290,7 +577,7 @@ mod tests {
         );
         let output = Core::new().get_render_state(&state);
         let serialized = format!("{:?}", output);
-        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 4, pager_window_height: 5, output_search_lines: [SearchLine { line: \"(?i)a\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"b\", context: 0, case_sensitive: true, inverse: false }], output_display_lines: [StringWithColorIndex([String(\"a\"), MatchString((\"b\", 0))]), StringWithColorIndex([String(\"A\"), MatchString((\"b\", 0))])], status_line: \"matchedLin\" }");
+        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 4, pager_window_height: 5, output_branch_lines: [], output_search_lines: [SearchLine { line: \"(?i)a\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"b\", context: Context { before: 0, after: 0 }, case_mode: Sensitive, inverse: false, literal: false, engine: Standard, query: false }], output_display_lines: [StringWithColorIndex([MatchString((\"a\", 18)), MatchString((\"b\", 0))]), StringWithColorIndex([MatchString((\"A\", 19)), MatchString((\"b\", 0))])], status_line: \"matchedLin\" }");
     }
 
     #[test]
@@ -315,7 +602,546 @@ mod tests {
         );
         let output = Core::new().get_render_state(&state);
         let serialized = format!("{:?}", output);
-        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 4, pager_window_height: 5, output_search_lines: [SearchLine { line: \"(?i)a\", context: 0, case_sensitive: false, inverse: true }, SearchLine { line: \"b\", context: 0, case_sensitive: true, inverse: true }], output_display_lines: [StringWithColorIndex([String(\"BB\")]), StringWithColorIndex([String(\"c\")])], status_line: \"matchedLin\" }");
+        assert_eq!(serialized, "RenderState { regex_valid: true, max_y: 10, max_x: 10, input_window_height: 4, pager_window_height: 5, output_branch_lines: [], output_search_lines: [SearchLine { line: \"(?i)a\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: true, literal: false, engine: Standard, query: false }, SearchLine { line: \"b\", context: Context { before: 0, after: 0 }, case_mode: Sensitive, inverse: true, literal: false, engine: Standard, query: false }], output_display_lines: [StringWithColorIndex([String(\"BB\")]), StringWithColorIndex([String(\"c\")])], status_line: \"matchedLin\" }");
+    }
+
+    #[test]
+    fn test_sub_search_beyond_first_chunk() {
+        let source_lines = (0..5000)
+            .map(|i| {
+                if i == 4000 {
+                    String::from("needle")
+                } else {
+                    format!("{}", i)
+                }
+            })
+            .collect::<Vec<String>>();
+        let state = State::new(
+            source_lines,
+            vec![
+                SearchLine::new(String::from("."), 0, false, false),
+                SearchLine::new(String::from("needle"), 0, false, false),
+            ],
+            0,
+            0,
+            10,
+            10,
+        );
+        let mut core = Core::new();
+        assert_eq!(core.get_full_output_string(&state), "needle");
+    }
+
+    #[test]
+    fn test_streaming_source() {
+        let source = Source::streaming();
+        let state = State::new_with_source(
+            source,
+            vec![
+                SearchLine::new(String::from("a"), 1, false, false),
+                SearchLine::new(String::from("b"), 0, false, false),
+            ],
+            0,
+            0,
+            10,
+            80,
+        );
+        let mut core = Core::new();
+        // The first stage waits for the line after "ab", which is its context
+        let state = state.append_source_lines(vec![String::from("ab")]);
+        let output = core.get_render_state(&state);
+        assert_eq!(output.output_display_lines.len(), 0);
+        assert_eq!(
+            output.status_line,
+            "matchedLines>0 pageY: 0, pageX: 0, context: 0, reading..."
+        );
+
+        let state = state.append_source_lines(vec![String::from("c")]);
+        let output = core.get_render_state(&state);
+        assert_eq!(output.output_display_lines.len(), 1);
+
+        let state = state
+            .append_source_lines(vec![String::from("b"), String::from("ba")])
+            .finish_source();
+        assert_eq!(core.get_full_output_string(&state), "ab\nba");
+        let output = core.get_render_state(&state);
+        assert_eq!(
+            output.status_line,
+            "matchedLines=2 pageY: 0, pageX: 0, context: 0"
+        );
+    }
+
+    #[test]
+    fn test_reload_error() {
+        let state = State::new(
+            vec![String::from("a")],
+            vec![SearchLine::new(String::from(""), 0, false, false)],
+            0,
+            0,
+            10,
+            80,
+        );
+        state
+            .source()
+            .set_reload_error(String::from("Failed to open 'a'"));
+        assert_eq!(
+            Core::new().get_render_state(&state).status_line,
+            "matchedLines=1 pageY: 0, pageX: 0, context: 0, reload failed: Failed to open 'a'"
+        );
+    }
+
+    #[test]
+    fn test_followed_source() {
+        let state = State::new(
+            vec![],
+            vec![
+                SearchLine::new(String::from("a"), 0, false, false),
+                SearchLine::new(String::from("b"), 0, false, false),
+            ],
+            0,
+            0,
+            10,
+            80,
+        )
+        .set_followed_lines(vec![String::from("ab"), String::from("ac")]);
+        let mut core = Core::new();
+        assert_eq!(core.get_full_output_string(&state), "ab");
+
+        // Appended lines reach every stage, not only the first
+        let state = state.append_source_lines(vec![String::from("abc"), String::from("b")]);
+        assert_eq!(core.get_full_output_string(&state), "ab\nabc");
+        assert_eq!(
+            core.get_render_state(&state).status_line,
+            "matchedLines>2 pageY: 0, pageX: 0, context: 0, reading..."
+        );
+    }
+
+    #[test]
+    fn test_file_names_in_sub_search() {
+        let source = Source::from_files(vec![
+            (
+                String::from("a.log"),
+                vec![String::from("ab"), String::from("ac")],
+            ),
+            (
+                String::from("long.log"),
+                vec![String::from("b"), String::from("abc")],
+            ),
+        ]);
+        let state = State::new_with_source(
+            source,
+            vec![
+                SearchLine::new(String::from("a"), 0, true, false),
+                SearchLine::new(String::from("b"), 0, true, false),
+            ],
+            0,
+            0,
+            10,
+            40,
+        );
+        let output = Core::new().get_render_state(&state);
+        let serialized = format!("{:?}", output.output_display_lines);
+        assert_eq!(serialized, "[StringWithColorIndex([Gutter(\"a.log:1    \"), MatchString((\"a\", 18)), MatchString((\"b\", 0))]), StringWithColorIndex([Gutter(\"long.log:2 \"), MatchString((\"a\", 18)), MatchString((\"b\", 0)), String(\"c\")])]");
+    }
+
+    #[test]
+    fn test_command_output() {
+        let source = Source::from_command(
+            vec![String::from("out")],
+            vec![String::from("error: bad")],
+            ExitStatus::from_raw(2 << 8),
+        );
+        let state = State::new_with_source(
+            source,
+            vec![SearchLine::new(String::from("bad"), 0, true, false)],
+            0,
+            0,
+            10,
+            80,
+        );
+        let output = Core::new().get_render_state(&state);
+        let serialized = format!("{:?}", output.output_display_lines);
+        assert_eq!(
+            serialized,
+            "[StringWithColorIndex([ErrorString(\"error: \"), MatchString((\"bad\", 0))])]"
+        );
+        assert!(output.status_line.ends_with(", exit code 2"));
+    }
+
+    fn record_output(record_mode: RecordMode, lines: &[&str], regex: &str, context: u32) -> String {
+        let state = State::new(
+            lines.iter().map(|l| l.to_string()).collect(),
+            vec![SearchLine::new(String::from(regex), context, true, false)],
+            0,
+            0,
+            20,
+            80,
+        );
+        let mut core = Core::with_record_mode(record_mode);
+        format!("{:?}", core.get_render_state(&state).output_display_lines)
+    }
+
+    #[test]
+    fn test_records_by_start_regex() {
+        let lines = [
+            "INFO started",
+            "ERROR failed",
+            "  at Foo.bar",
+            "  at Foo.main",
+            "INFO done",
+        ];
+        let record_start = RecordMode::Start(regex::Regex::new("^[A-Z]+ ").unwrap());
+        assert_eq!(
+            record_output(record_start.clone(), &lines, "main", 0),
+            "[StringWithColorIndex([String(\"ERROR failed\")]), StringWithColorIndex([String(\"  at Foo.bar\")]), StringWithColorIndex([String(\"  at Foo.\"), MatchString((\"main\", 0))])]"
+        );
+        assert_eq!(
+            record_output(record_start, &lines, "INFO", 0),
+            "[StringWithColorIndex([MatchString((\"INFO\", 0)), String(\" started\")]), BreakLine, StringWithColorIndex([MatchString((\"INFO\", 0)), String(\" done\")])]"
+        );
+    }
+
+    #[test]
+    fn test_paragraph_records_with_context() {
+        let lines = ["a", "", "b", "b2", "", "", "c", "", "d"];
+        assert_eq!(
+            record_output(RecordMode::Paragraphs, &lines, "b2", 1),
+            "[StringWithColorIndex([String(\"a\")]), BreakLine, StringWithColorIndex([String(\"b\")]), StringWithColorIndex([MatchString((\"b2\", 0))]), BreakLine, StringWithColorIndex([String(\"c\")])]"
+        );
+    }
+
+    #[test]
+    fn test_records_in_sub_search() {
+        let state = State::new(
+            ["x 1", "one", "", "x 2", "two", "", "y 3", "one"]
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+            vec![
+                SearchLine::new(String::from("x"), 0, true, false),
+                SearchLine::new(String::from("one"), 0, true, false),
+            ],
+            0,
+            0,
+            20,
+            80,
+        );
+        let mut core = Core::with_record_mode(RecordMode::Paragraphs);
+        assert_eq!(core.get_full_output_string(&state), "x 1\none");
+    }
+
+    #[test]
+    fn test_record_context_in_sub_search() {
+        let state = State::new(
+            ["x 1", "one", "", "y 2", "two", "", "x 3", "one", "", "y 4"]
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+            vec![
+                SearchLine::new(String::from("x"), 0, true, false),
+                SearchLine::new(String::from("3"), 1, true, false),
+            ],
+            0,
+            0,
+            20,
+            80,
+        );
+        // The neighbouring records in the input, not the other records matching "x"
+        let mut core = Core::with_record_mode(RecordMode::Paragraphs);
+        assert_eq!(
+            core.get_full_output_string(&state),
+            "y 2\ntwo\nx 3\none\ny 4"
+        );
+        let mut core = Core::with_record_mode(RecordMode::Paragraphs).with_filtered_context(true);
+        assert_eq!(core.get_full_output_string(&state), "x 1\none\nx 3\none");
+    }
+
+    #[test]
+    fn test_fuzzy_ranking() {
+        let fuzzy_state = |ranked: bool| {
+            State::new(
+                ["xfxxbx", "other", "foo_bar", "fb", "bf"]
+                    .iter()
+                    .map(|l| l.to_string())
+                    .collect(),
+                vec![SearchLine {
+                    engine: Engine::Fuzzy { ranked },
+                    ..SearchLine::new(String::from("fb"), 1, false, false)
+                }],
+                0,
+                0,
+                20,
+                80,
+            )
+        };
+        let mut core = Core::new();
+        assert_eq!(
+            core.get_full_output_string(&fuzzy_state(true)),
+            "fb\nfoo_bar\nxfxxbx"
+        );
+        assert_eq!(
+            core.get_full_output_string(&fuzzy_state(false)),
+            "xfxxbx\nother\nfoo_bar\nfb\nbf"
+        );
+        let output = core.get_render_state(&fuzzy_state(true));
+        assert_eq!(
+            format!("{:?}", output.output_display_lines[1]),
+            "StringWithColorIndex([MatchString((\"f\", 1)), String(\"oo_\"), MatchString((\"b\", 2)), String(\"ar\")])"
+        );
+    }
+
+    #[test]
+    fn test_union() {
+        let source_lines: Vec<String> = ["a1", "b2", "a2", "ab", "c3", "a3b"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(String::from("c|b$"), 0, true, false)],
+            0,
+            0,
+            20,
+            80,
+        )
+        .branch()
+        .push_search_char('a')
+        .accept_partial_match()
+        .push_search_char('1')
+        .branch()
+        .push_search_char('b');
+        let mut core = Core::new();
+        assert_eq!(core.get_full_output_string(&state), "a1\nab\nc3\na3b");
+        let output = core.get_render_state(&state);
+        assert_eq!(output.output_branch_lines, vec!["c|b$", "a > 1"]);
+        assert_eq!(
+            format!("{:?}", output.output_display_lines[3]),
+            "StringWithColorIndex([String(\"a3\"), MatchString((\"b\", 1))])"
+        );
+
+        // Merged across several request chunks
+        let source_lines: Vec<String> = (0..5000).map(|i| i.to_string()).collect();
+        let state = State::new(
+            source_lines.clone(),
+            vec![SearchLine::new(String::from("^1"), 0, true, false)],
+            0,
+            0,
+            20,
+            80,
+        )
+        .branch()
+        .push_search_char('7')
+        .push_search_char('$');
+        let expected: Vec<String> = source_lines
+            .into_iter()
+            .filter(|l| l.starts_with('1') || l.ends_with('7'))
+            .collect();
+        assert_eq!(core.get_full_output(&state), expected);
+    }
+
+    #[test]
+    fn test_union_branch_contexts() {
+        let source_lines: Vec<String> = ["a", "x1", "x2", "x3", "b", "x4", "x5"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(String::from("a"), 1, true, false)],
+            0,
+            0,
+            20,
+            80,
+        )
+        .branch()
+        .push_search_char('b')
+        .modify_context(-1);
+        let mut core = Core::new();
+        assert_eq!(core.get_full_output_string(&state), "a\nx1\nb");
+        let state = state.modify_context(2);
+        assert_eq!(
+            core.get_full_output_string(&state),
+            "a\nx1\nx2\nx3\nb\nx4\nx5"
+        );
+    }
+
+    #[test]
+    fn test_union_branch_context_breaks() {
+        let source_lines: Vec<String> = ["x0", "x1", "x2", "x3", "x4", "a", "m"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(String::from("a"), 1, true, false)],
+            0,
+            0,
+            20,
+            80,
+        )
+        .branch()
+        .push_search_char('m')
+        .modify_context(3);
+        let mut core = Core::new();
+        // The break before the context of "a" is within the context of "m"
+        assert_eq!(core.get_full_output_string(&state), "x2\nx3\nx4\na\nm");
+        let output = core.get_render_state(&state);
+        assert_eq!(
+            format!("{:?}", &output.output_display_lines[..2]),
+            "[BreakLine, StringWithColorIndex([String(\"x2\")])]"
+        );
+        assert_eq!(output.output_display_lines.len(), 6);
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let source_lines: Vec<String> = ["a1", "b", "a2", "c", "a3"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let state = State::new(
+            source_lines,
+            vec![
+                SearchLine::new(String::from("a"), 0, true, false),
+                SearchLine::new(String::from("[13]"), 1, true, false),
+            ],
+            0,
+            0,
+            20,
+            80,
+        )
+        .toggle_line_numbers();
+        let mut core = Core::new();
+        assert_eq!(core.get_full_output_string(&state), "1:a1\n2-b\n4-c\n5:a3");
+        let output = core.get_render_state(&state);
+        assert_eq!(
+            format!("{:?}", output.output_display_lines),
+            "[StringWithColorIndex([Gutter(\"1: \"), MatchString((\"a\", 18)), MatchString((\"1\", 0))]), \
+             StringWithColorIndex([Gutter(\"2- \"), String(\"b\")]), \
+             BreakLine, \
+             StringWithColorIndex([Gutter(\"4- \"), String(\"c\")]), \
+             StringWithColorIndex([Gutter(\"5: \"), MatchString((\"a\", 18)), MatchString((\"3\", 1))])]"
+        );
+        let state = state.toggle_line_numbers();
+        assert_eq!(core.get_full_output_string(&state), "a1\nb\nc\na3");
+    }
+
+    #[test]
+    fn test_only_matching() {
+        let source_lines: Vec<String> = ["a1 b22", "none", "c333"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(String::from("[0-9]+"), 1, true, false)],
+            0,
+            0,
+            20,
+            80,
+        );
+        let mut core = Core::new();
+        assert_eq!(core.get_full_output_string(&state), "a1 b22\nnone\nc333");
+        let state = state.cycle_display_mode();
+        assert_eq!(core.get_full_output_string(&state), "1\n22\n333");
+        let output = core.get_render_state(&state);
+        assert_eq!(
+            format!("{:?}", output.output_display_lines),
+            "[StringWithColorIndex([Gutter(\"1: \"), MatchString((\"1\", 0))]), \
+             StringWithColorIndex([Gutter(\"1: \"), MatchString((\"22\", 1))]), \
+             StringWithColorIndex([Gutter(\"3: \"), MatchString((\"333\", 2))])]"
+        );
+        let state = state
+            .cycle_display_mode()
+            .cycle_display_mode()
+            .cycle_display_mode();
+        assert_eq!(state.display_mode(), DisplayMode::Lines);
+        assert_eq!(
+            format!("{:?}", core.get_current_output_length(&state)),
+            "Is(3)"
+        );
+    }
+
+    #[test]
+    fn test_unique() {
+        let source_lines: Vec<String> = ["E2 E1", "ok", "E2", "E1 E2", "E2"]
+            .iter()
+            .map(|l| l.to_string())
+            .collect();
+        let state = State::new(
+            source_lines,
+            vec![SearchLine::new(String::from("E[0-9]"), 1, true, false)],
+            0,
+            0,
+            20,
+            80,
+        )
+        .cycle_display_mode()
+        .cycle_display_mode();
+        let mut core = Core::new();
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      4 E2", "      2 E1"]
+        );
+        let state = state.toggle_unique_order();
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      2 E1", "      4 E2"]
+        );
+        let output = core.get_render_state(&state);
+        assert_eq!(
+            format!("{:?}", output.output_display_lines[0]),
+            "StringWithColorIndex([Gutter(\"2 \"), MatchString((\"E1\", 0))])"
+        );
+
+        let state = state.cycle_display_mode();
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      1 E1 E2", "      2 E2", "      1 E2 E1"]
+        );
+        let state = state.toggle_unique_order();
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      2 E2", "      1 E1 E2", "      1 E2 E1"]
+        );
+    }
+
+    #[test]
+    fn test_unique_streaming_source() {
+        let state = State::new_with_source(
+            Source::streaming(),
+            vec![SearchLine::new(String::from("E[0-9]"), 0, true, false)],
+            0,
+            0,
+            20,
+            80,
+        )
+        .cycle_display_mode()
+        .cycle_display_mode()
+        .append_source_lines(vec![String::from("E1 E2"), String::from("E2")]);
+        let mut core = Core::new();
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      2 E2", "      1 E1"]
+        );
+
+        // Only the appended lines are counted on top of the earlier ones
+        let state = state.append_source_lines(vec![String::from("E1"), String::from("E1")]);
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      3 E1", "      2 E2"]
+        );
+        let state = state.cycle_display_mode();
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      2 E1", "      1 E1 E2", "      1 E2"]
+        );
+        let state = state
+            .append_source_lines(vec![String::from("E2")])
+            .finish_source();
+        assert_eq!(
+            core.get_full_output(&state),
+            vec!["      2 E1", "      2 E2", "      1 E1 E2"]
+        );
     }
 
     #[test]
@@ -421,7 +1247,7 @@ mod tests {
             Err(e) => {
                 println!("Error reading existing snapshot, writing new: {:?}", e);
                 write_to_disk(test_name, &test_results).unwrap();
-                assert!(false, "No snapshot found");
+                panic!("No snapshot found");
             }
         }
     }
@@ -0,0 +1,697 @@
+use crate::igrepper::types::MatchPosition;
+use std::fmt;
+use std::ops::Range;
+
+/// How a search line is matched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Engine {
+    /// The `regex` crate, which runs in linear time.
+    #[default]
+    Standard,
+    /// `fancy-regex`, which adds lookaround and backreferences, like
+    /// `grep --perl-regexp`. Only used for patterns that need it.
+    Fancy,
+    /// Not a regex, but characters that have to appear in order, possibly
+    /// with gaps, like fzf. If `ranked`, the best matches are shown first.
+    Fuzzy { ranked: bool },
+}
+
+impl Engine {
+    /// The engine following this one when cycling through them.
+    pub fn next(self) -> Engine {
+        match self {
+            Engine::Standard => Engine::Fancy,
+            Engine::Fancy => Engine::Fuzzy { ranked: true },
+            Engine::Fuzzy { .. } => Engine::Standard,
+        }
+    }
+
+    /// Switches between ranked and source order, for the fuzzy engine.
+    pub fn toggle_ranked(self) -> Engine {
+        match self {
+            Engine::Fuzzy { ranked } => Engine::Fuzzy { ranked: !ranked },
+            engine => engine,
+        }
+    }
+}
+
+/// How the matches of the current search line are coloured.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ColorMode {
+    /// Each distinct matched text in a colour of its own.
+    #[default]
+    Text,
+    /// Each capture group of a regex in a colour of its own. Where groups are
+    /// nested, the innermost one decides the colour.
+    Group,
+    /// Each alternative of the top-level `|` of a regex in a colour of its own.
+    Branch,
+}
+
+impl ColorMode {
+    /// The mode following this one when cycling through them.
+    pub fn next(self) -> ColorMode {
+        match self {
+            ColorMode::Text => ColorMode::Group,
+            ColorMode::Group => ColorMode::Branch,
+            ColorMode::Branch => ColorMode::Text,
+        }
+    }
+}
+
+/// A compiled search line.
+#[derive(Clone)]
+pub enum Matcher {
+    Standard(regex::Regex),
+    Fancy(fancy_regex::Regex),
+    Fuzzy(FuzzyPattern),
+    Query(Query),
+    Union(Union),
+}
+
+/// Terms that all have to match, in any order, each with alternatives,
+/// and terms that must not match.
+#[derive(Clone)]
+pub struct Query {
+    text: String,
+    positive: Vec<Vec<Matcher>>,
+    negative: Vec<Matcher>,
+}
+
+/// The last stages of the branches of a union, see `Matcher::union`.
+#[derive(Clone)]
+pub struct Union {
+    text: String,
+    matchers: Vec<Matcher>,
+}
+
+#[derive(Clone)]
+pub struct FuzzyPattern {
+    pattern: String,
+    case_sensitive: bool,
+    ranked: bool,
+}
+
+/// Splits the matches of a regex into parts coloured by capture group or by
+/// branch, see `Matcher::coloring`.
+pub enum Coloring {
+    Groups(Matcher),
+    /// The top-level alternatives of the regex, each compiled on its own.
+    Branches(Vec<Matcher>),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Standard(regex::Error),
+    Fancy(Box<fancy_regex::Error>),
+}
+
+impl Matcher {
+    /// Compiles the regex `pattern` with the standard engine if it can, which
+    /// is much faster, and otherwise with the fancy engine if `engine` allows it.
+    pub fn new(pattern: &str, engine: Engine) -> Result<Matcher, Error> {
+        match (regex::Regex::new(pattern), engine) {
+            (Ok(regex), _) => Ok(Matcher::Standard(regex)),
+            (Err(_), Engine::Fancy) => fancy_regex::Regex::new(pattern)
+                .map(Matcher::Fancy)
+                .map_err(|e| Error::Fancy(Box::new(e))),
+            (Err(e), _) => Err(Error::Standard(e)),
+        }
+    }
+
+    /// Matches the characters of `pattern` in order, possibly with gaps.
+    pub fn fuzzy(pattern: &str, case_sensitive: bool, ranked: bool) -> Matcher {
+        Matcher::Fuzzy(FuzzyPattern {
+            pattern: String::from(pattern),
+            case_sensitive,
+            ranked,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Matcher::Standard(regex) => regex.as_str(),
+            Matcher::Fancy(regex) => regex.as_str(),
+            Matcher::Fuzzy(fuzzy) => &fuzzy.pattern,
+            Matcher::Query(query) => &query.text,
+            Matcher::Union(union) => &union.text,
+        }
+    }
+
+    /// Highlights the matches of any of `matchers`, for showing the output of
+    /// several branches together. Lines without any match are kept, as the
+    /// branches have already filtered them.
+    pub fn union(matchers: Vec<Matcher>) -> Matcher {
+        Matcher::Union(Union {
+            text: matchers
+                .iter()
+                .map(|m| m.as_str())
+                .collect::<Vec<&str>>()
+                .join(" + "),
+            matchers,
+        })
+    }
+
+    /// Parses `text` as space separated terms, which all have to match.
+    /// Terms starting with `!` must not match, and `|` outside of groups
+    /// separates alternatives.
+    /// Each term is compiled using `compile`.
+    pub fn query<F: Fn(&str) -> Result<Matcher, Error>>(
+        text: &str,
+        compile: F,
+    ) -> Result<Matcher, Error> {
+        let mut positive: Vec<Vec<Matcher>> = vec![];
+        let mut negative: Vec<Matcher> = vec![];
+        for term in text.split_whitespace() {
+            match term.strip_prefix('!') {
+                Some("") => {}
+                Some(excluded) => negative.push(compile(excluded)?),
+                None => positive.push(
+                    split_top_level(term)
+                        .into_iter()
+                        .filter(|alternative| !alternative.is_empty())
+                        .map(&compile)
+                        .collect::<Result<Vec<Matcher>, Error>>()?,
+                ),
+            }
+        }
+        positive.retain(|alternatives| !alternatives.is_empty());
+        Ok(Matcher::Query(Query {
+            text: String::from(text),
+            positive,
+            negative,
+        }))
+    }
+
+    /// How to colour the matches in `mode`, or `None` if they are coloured by
+    /// their text. Only regexes have groups and branches.
+    pub fn coloring(&self, mode: ColorMode) -> Option<Coloring> {
+        match (self, mode) {
+            (_, ColorMode::Text)
+            | (Matcher::Fuzzy(_) | Matcher::Query(_) | Matcher::Union(_), _) => None,
+            (_, ColorMode::Group) => Some(Coloring::Groups(self.clone())),
+            (_, ColorMode::Branch) => top_level_branches(self.as_str())
+                .iter()
+                .map(|branch| Matcher::new(branch, Engine::Fancy).ok())
+                .collect::<Option<Vec<Matcher>>>()
+                .map(Coloring::Branches),
+        }
+    }
+
+    /// The byte range of the first match starting at or after `start`.
+    fn find_at(&self, line: &str, start: usize) -> Option<Range<usize>> {
+        match self {
+            Matcher::Standard(regex) => regex.find_at(line, start).map(|m| m.range()),
+            Matcher::Fancy(regex) => regex
+                .find_from_pos(line, start)
+                .ok()
+                .flatten()
+                .map(|m| m.range()),
+            _ => None,
+        }
+    }
+
+    /// The byte ranges of the capture groups of the first match starting at
+    /// or after `start`, the whole match first. Groups that did not take part
+    /// in the match are `None`.
+    fn groups_at(&self, line: &str, start: usize) -> Vec<Option<Range<usize>>> {
+        match self {
+            Matcher::Standard(regex) => regex
+                .captures_at(line, start)
+                .map(|c| c.iter().map(|m| m.map(|m| m.range())).collect())
+                .unwrap_or_default(),
+            Matcher::Fancy(regex) => regex
+                .captures_from_pos(line, start)
+                .ok()
+                .flatten()
+                .map(|c| c.iter().map(|m| m.map(|m| m.range())).collect())
+                .unwrap_or_default(),
+            _ => vec![],
+        }
+    }
+
+    /// Whether matching lines are ordered by how well they match.
+    pub fn is_ranked(&self) -> bool {
+        matches!(self, Matcher::Fuzzy(FuzzyPattern { ranked: true, .. }))
+    }
+
+    /// The byte ranges of all non-overlapping matches in `line`. The fancy
+    /// engine stops at the first match that exceeds its backtracking limit.
+    pub fn find_iter(&self, line: &str) -> Vec<Range<usize>> {
+        match self {
+            Matcher::Standard(regex) => regex.find_iter(line).map(|m| m.range()).collect(),
+            Matcher::Fancy(regex) => regex
+                .find_iter(line)
+                .map_while(|m| m.ok())
+                .map(|m| m.range())
+                .collect(),
+            Matcher::Fuzzy(fuzzy) => fuzzy.find(line).unwrap_or_default(),
+            Matcher::Query(_) | Matcher::Union(_) => self
+                .find_matches(line)
+                .into_iter()
+                .map(|m| m.start as usize..m.end as usize)
+                .collect(),
+        }
+    }
+
+    /// Like `find_iter`, but the matches of each term of a query are in a
+    /// colour group of their own.
+    pub fn find_matches(&self, line: &str) -> Vec<MatchPosition> {
+        match self {
+            Matcher::Query(query) => query.find(line),
+            Matcher::Union(union) => union.find(line),
+            _ => self
+                .find_iter(line)
+                .into_iter()
+                .map(|m| MatchPosition {
+                    start: m.start as u32,
+                    end: m.end as u32,
+                    color_group: None,
+                    stage: 0,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Query {
+    /// The matches of all positive terms, if they all match and no negative
+    /// term does. Where matches overlap, the first one is kept.
+    fn find(&self, line: &str) -> Vec<MatchPosition> {
+        if self.negative.iter().any(|m| !m.find_iter(line).is_empty()) {
+            return vec![];
+        }
+        if self.positive.is_empty() {
+            return vec![MatchPosition {
+                start: 0,
+                end: 0,
+                color_group: None,
+                stage: 0,
+            }];
+        }
+        let mut matches: Vec<MatchPosition> = vec![];
+        for (group, alternatives) in self.positive.iter().enumerate() {
+            let term_matches: Vec<MatchPosition> = alternatives
+                .iter()
+                .flat_map(|m| m.find_iter(line))
+                .map(|m| MatchPosition {
+                    start: m.start as u32,
+                    end: m.end as u32,
+                    color_group: Some(group as u32),
+                    stage: 0,
+                })
+                .collect();
+            if term_matches.is_empty() {
+                return vec![];
+            }
+            matches.extend(term_matches);
+        }
+        without_overlaps(matches)
+    }
+}
+
+impl Union {
+    /// The matches of all branches, or an empty match if there are none.
+    fn find(&self, line: &str) -> Vec<MatchPosition> {
+        let matches = without_overlaps(
+            self.matchers
+                .iter()
+                .flat_map(|m| m.find_matches(line))
+                .collect(),
+        );
+        if matches.is_empty() {
+            vec![MatchPosition {
+                start: 0,
+                end: 0,
+                color_group: None,
+                stage: 0,
+            }]
+        } else {
+            matches
+        }
+    }
+}
+
+impl Coloring {
+    /// Splits `m`, a match on `line`, into parts with the colour group of
+    /// their capture group or branch. The text of a match outside of any
+    /// capture group is in group 0.
+    pub fn split(&self, line: &str, m: &MatchPosition) -> Vec<MatchPosition> {
+        let (start, end) = (m.start as usize, m.end as usize);
+        let part = |range: Range<usize>, group: usize| MatchPosition {
+            start: range.start as u32,
+            end: range.end as u32,
+            color_group: Some(group as u32),
+            stage: m.stage,
+        };
+        let parts = match self {
+            Coloring::Branches(branches) => branches
+                .iter()
+                .position(|branch| branch.find_at(line, start).map(|r| r.start) == Some(start))
+                .map(|branch| vec![part(start..end, branch)]),
+            Coloring::Groups(regex) => {
+                let groups = regex.groups_at(line, start);
+                if start == end || groups.first() != Some(&Some(start..end)) {
+                    return vec![m.clone()];
+                }
+                let mut boundaries: Vec<usize> = groups
+                    .iter()
+                    .flatten()
+                    .flat_map(|group| vec![group.start, group.end])
+                    .collect();
+                boundaries.sort_unstable();
+                boundaries.dedup();
+                // Groups are numbered by their opening parenthesis, so the
+                // innermost group around a part is the last one.
+                let mut parts: Vec<MatchPosition> = vec![];
+                for bounds in boundaries.windows(2) {
+                    let group = groups
+                        .iter()
+                        .rposition(|group| {
+                            group.as_ref().is_some_and(|group| {
+                                group.start <= bounds[0] && bounds[1] <= group.end
+                            })
+                        })
+                        .unwrap_or(0);
+                    match parts.last_mut() {
+                        Some(last) if last.color_group == Some(group as u32) => {
+                            last.end = bounds[1] as u32
+                        }
+                        _ => parts.push(part(bounds[0]..bounds[1], group)),
+                    }
+                }
+                Some(parts)
+            }
+        };
+        parts.unwrap_or_else(|| vec![m.clone()])
+    }
+}
+
+/// Splits `pattern` at each `|` outside of groups and character classes.
+/// Flags set at the start of the pattern, like `(?i)`, apply to all branches.
+fn top_level_branches(pattern: &str) -> Vec<String> {
+    let flags = leading_flags(pattern);
+    split_top_level(&pattern[flags.len()..])
+        .into_iter()
+        .map(|branch| format!("{}{}", flags, branch))
+        .collect()
+}
+
+/// Splits `pattern` at each `|` outside of groups, character classes and
+/// escapes, so `(a|b)` and `x{1,2}|y` are split like the regex would.
+pub(crate) fn split_top_level(pattern: &str) -> Vec<&str> {
+    let mut branches = vec![];
+    let mut branch_start = 0;
+    let mut group_depth = 0;
+    let mut class_depth = 0;
+    let mut chars = pattern.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => {
+                class_depth += 1;
+                // A `]` first in a class, possibly negated, is a literal
+                chars.next_if(|&(_, c)| c == '^');
+                chars.next_if(|&(_, c)| c == ']');
+            }
+            ']' if class_depth > 0 => class_depth -= 1,
+            _ if class_depth > 0 => {}
+            '(' => group_depth += 1,
+            ')' => group_depth -= 1,
+            '|' if group_depth == 0 => {
+                branches.push(&pattern[branch_start..i]);
+                branch_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    branches.push(&pattern[branch_start..]);
+    branches
+}
+
+/// The groups setting flags at the start of `pattern`, like `(?i)`.
+fn leading_flags(pattern: &str) -> &str {
+    let mut end = 0;
+    while let Some(group) = pattern[end..].strip_prefix("(?") {
+        match group.find(')') {
+            Some(len)
+                if len > 0
+                    && group[..len]
+                        .chars()
+                        .all(|c| c.is_ascii_alphabetic() || c == '-') =>
+            {
+                end += len + 3
+            }
+            _ => break,
+        }
+    }
+    &pattern[..end]
+}
+
+/// Sorts `matches`, keeping the first of any that overlap.
+fn without_overlaps(mut matches: Vec<MatchPosition>) -> Vec<MatchPosition> {
+    matches.sort_by_key(|m| (m.start, m.color_group));
+    let mut end_of_last_match = 0;
+    matches.retain(|m| {
+        let keep = m.start >= end_of_last_match;
+        if keep {
+            end_of_last_match = m.end;
+        }
+        keep
+    });
+    matches
+}
+
+impl FuzzyPattern {
+    fn chars_match(&self, a: char, b: char) -> bool {
+        a == b || (!self.case_sensitive && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    /// The byte ranges of the matched characters, using the shortest part of
+    /// `line` that contains the pattern, so that the matches are close together.
+    fn find(&self, line: &str) -> Option<Vec<Range<usize>>> {
+        let pattern: Vec<char> = self.pattern.chars().collect();
+        if pattern.is_empty() {
+            return Some(vec![Range { start: 0, end: 0 }]);
+        }
+        let chars: Vec<(usize, char)> = line.char_indices().collect();
+
+        // The end of the first match, then the latest start of a match ending there
+        let mut matched = 0;
+        let end = chars.iter().position(|&(_, c)| {
+            if self.chars_match(c, pattern[matched]) {
+                matched += 1;
+            }
+            matched == pattern.len()
+        })?;
+        let start = (0..=end)
+            .rev()
+            .find(|&i| {
+                if self.chars_match(chars[i].1, pattern[matched - 1]) {
+                    matched -= 1;
+                }
+                matched == 0
+            })
+            .unwrap();
+
+        let mut ranges: Vec<Range<usize>> = vec![];
+        for &(byte_index, c) in &chars[start..=end] {
+            if matched < pattern.len() && self.chars_match(c, pattern[matched]) {
+                matched += 1;
+                let char_end = byte_index + c.len_utf8();
+                match ranges.last_mut() {
+                    Some(range) if range.end == byte_index => range.end = char_end,
+                    _ => ranges.push(byte_index..char_end),
+                }
+            }
+        }
+        Some(ranges)
+    }
+}
+
+/// Rates a fuzzy match, similar to fzf. Consecutive characters and matches
+/// at the start of words score higher, gaps between matches score lower.
+pub fn fuzzy_score(line: &str, matches: &[Range<usize>]) -> i64 {
+    let mut score = 0i64;
+    let mut previous_end: Option<usize> = None;
+    for range in matches.iter().filter(|r| !r.is_empty()) {
+        let length = line[range.clone()].chars().count() as i64;
+        score += length * 16 + (length - 1) * 8;
+        let before = line[..range.start].chars().next_back();
+        let first = line[range.clone()].chars().next().unwrap();
+        let word_start = match before {
+            None => true,
+            Some(before) => {
+                !before.is_alphanumeric() || (before.is_lowercase() && first.is_uppercase())
+            }
+        };
+        if word_start {
+            score += 8;
+        }
+        if let Some(previous_end) = previous_end {
+            score -= 3 + line[previous_end..range.start].chars().count() as i64;
+        }
+        previous_end = Some(range.end);
+    }
+    score
+}
+
+impl fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Standard(regex) => regex.fmt(f),
+            Matcher::Fancy(regex) => f.debug_tuple("FancyRegex").field(&regex.as_str()).finish(),
+            Matcher::Fuzzy(fuzzy) => f.debug_tuple("Fuzzy").field(&fuzzy.pattern).finish(),
+            Matcher::Query(query) => f.debug_tuple("Query").field(&query.text).finish(),
+            Matcher::Union(union) => f.debug_tuple("Union").field(&union.text).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Standard(e) => e.fmt(f),
+            Error::Fancy(e) => e.fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn fancy_only_when_needed() {
+        let matcher = Matcher::new("a+", Engine::Fancy).unwrap();
+        assert_eq!(format!("{:?}", matcher), "Regex(\"a+\")");
+
+        assert!(Matcher::new("a(?=b)", Engine::Standard).is_err());
+        let matcher = Matcher::new("a(?=b)", Engine::Fancy).unwrap();
+        assert_eq!(format!("{:?}", matcher), "FancyRegex(\"a(?=b)\")");
+        assert_eq!(matcher.find_iter("ab ac ab"), vec![0..1, 6..7]);
+    }
+
+    #[test]
+    fn fuzzy_matches_shortest_part() {
+        let matcher = Matcher::fuzzy("abc", false, true);
+        assert_eq!(matcher.find_iter("a xAbyc"), vec![3..5, 6..7]);
+        assert!(matcher.find_iter("acb").is_empty());
+        assert_eq!(Matcher::fuzzy("A", true, true).find_iter("aA"), vec![1..2]);
+        assert_eq!(Matcher::fuzzy("ö", false, true).find_iter("xÖ"), vec![1..3]);
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_consecutive_and_word_starts() {
+        let score =
+            |line: &str| fuzzy_score(line, &Matcher::fuzzy("fb", false, true).find_iter(line));
+        assert!(score("foo_bar") > score("xfxxbx"));
+        assert!(score("fb") > score("foo_bar"));
+        assert!(score("fooBar") > score("foobar"));
+    }
+
+    #[test]
+    fn query_terms() {
+        let query =
+            Matcher::query("b|x a !c", |term| Matcher::new(term, Engine::Standard)).unwrap();
+        let matches = |line: &str| -> Vec<(u32, u32, Option<u32>)> {
+            query
+                .find_matches(line)
+                .iter()
+                .map(|m| (m.start, m.end, m.color_group))
+                .collect()
+        };
+        assert_eq!(
+            matches("a x b"),
+            vec![(0, 1, Some(1)), (2, 3, Some(0)), (4, 5, Some(0))]
+        );
+        assert!(matches("a only").is_empty());
+        assert!(matches("a b c").is_empty());
+        assert!(Matcher::query("a (", |term| Matcher::new(term, Engine::Standard)).is_err());
+
+        // Alternations within a group are part of the term's regex
+        let query = Matcher::query("(a|b)c x{1,2}|y", |term| {
+            Matcher::new(term, Engine::Standard)
+        })
+        .unwrap();
+        assert!(!query.find_matches("bc y").is_empty());
+        assert!(!query.find_matches("ac xx").is_empty());
+        assert!(query.find_matches("a b y").is_empty());
+        assert!(query.find_matches("bc z").is_empty());
+    }
+
+    #[test]
+    fn union_keeps_lines_without_matches() {
+        let union = Matcher::union(vec![
+            Matcher::new("b+", Engine::Standard).unwrap(),
+            Matcher::new("ab", Engine::Standard).unwrap(),
+        ]);
+        assert_eq!(format!("{:?}", union), "Union(\"b+ + ab\")");
+        assert_eq!(union.find_iter("abb c ab"), vec![0..2, 6..8]);
+        assert_eq!(union.find_iter("c"), vec![0..0]);
+    }
+
+    #[test]
+    fn split_by_group_and_branch() {
+        let split = |pattern: &str, mode: ColorMode, line: &str| -> Vec<(u32, u32, Option<u32>)> {
+            let matcher = Matcher::new(pattern, Engine::Fancy).unwrap();
+            let coloring = matcher.coloring(mode).unwrap();
+            matcher
+                .find_matches(line)
+                .iter()
+                .flat_map(|m| coloring.split(line, m))
+                .map(|m| (m.start, m.end, m.color_group))
+                .collect()
+        };
+        assert_eq!(
+            split(r"(GET|POST) (\S+) (\d{3})", ColorMode::Group, "GET /a 200"),
+            vec![
+                (0, 3, Some(1)),
+                (3, 4, Some(0)),
+                (4, 6, Some(2)),
+                (6, 7, Some(0)),
+                (7, 10, Some(3))
+            ]
+        );
+        assert_eq!(
+            split("x(a(b)c)", ColorMode::Group, "xabc"),
+            vec![
+                (0, 1, Some(0)),
+                (1, 2, Some(1)),
+                (2, 3, Some(2)),
+                (3, 4, Some(1))
+            ]
+        );
+        assert_eq!(
+            split("(?i)get|p(o|u)st", ColorMode::Branch, "x PUT post GET"),
+            vec![(6, 10, Some(1)), (11, 14, Some(0))]
+        );
+        assert!(Matcher::fuzzy("a", false, true)
+            .coloring(ColorMode::Group)
+            .is_none());
+    }
+
+    #[test]
+    fn top_level_branches_skip_groups_and_classes() {
+        assert_eq!(
+            top_level_branches(r"(?i)a(b|c)|[|\]]|d\|e|[]|]"),
+            vec!["(?i)a(b|c)", r"(?i)[|\]]", r"(?i)d\|e", "(?i)[]|]"]
+        );
+        assert_eq!(top_level_branches("a"), vec!["a"]);
+    }
+
+    #[test]
+    fn fancy_backreference() {
+        let matcher = Matcher::new(r"(\w)\1", Engine::Fancy).unwrap();
+        assert_eq!(matcher.find_iter("abba cc"), vec![1..3, 5..7]);
+    }
+}
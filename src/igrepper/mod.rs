@@ -3,9 +3,10 @@ use ncurses::{
     KEY_RESIZE, KEY_RIGHT, KEY_UP,
 };
 use std::cmp;
-use std::io::Write;
+use std::io::{BufReader, Write};
 use std::process::{Command, Stdio};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 use std::{char, thread};
 
 extern crate ncurses;
@@ -13,25 +14,35 @@ extern crate regex;
 
 pub mod constants;
 pub mod core;
+pub mod matcher;
 pub mod output_generator;
 pub mod rendering;
+pub mod source;
 mod source_lines;
 pub mod state;
 pub mod trimming;
 pub mod types;
 
-use crate::file_reading::SourceProducer;
+use crate::file_reading::{self, FileFollower, FollowUpdate, SourceProducer};
 use crate::igrepper::constants::*;
 use crate::igrepper::core::Core;
-use crate::igrepper::output_generator::Len;
+use crate::igrepper::matcher::{split_top_level, Engine};
+use crate::igrepper::output_generator::{DisplayMode, Len, RecordMode, UniqueOrder};
 use crate::igrepper::rendering::clear_screen;
+use crate::igrepper::source::Source;
 use crate::igrepper::state::{SearchLine, State};
-use anyhow::Result;
-use inotify::Inotify;
+use anyhow::{anyhow, Context, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::path::Path;
 
 pub enum Message {
     Character(i32),
-    ReloadFile,
+    /// The input has been read again.
+    Reloaded(Source),
+    ReloadFailed(String),
+    AppendLines(Vec<String>),
+    ReplaceSource(Vec<String>),
+    EndOfInput,
     Error(String),
 }
 
@@ -48,13 +59,39 @@ fn get_screen_size() -> (u32, u32) {
 }
 
 pub fn igrepper(
-    source_producer: SourceProducer,
-    initial_context: u32,
-    initial_regex: Option<&str>,
+    mut source_producer: SourceProducer,
+    initial_search_line: SearchLine,
     inotify_option: Option<Inotify>,
+    reload_interval: Option<Duration>,
+    record_mode: RecordMode,
+    filtered_context: bool,
     external_editor: Vec<String>,
 ) -> Result<()> {
-    let source = source_producer.get_source()?;
+    let mut follower = match inotify_option {
+        Some(_) => Some(source_producer.follow()?),
+        None => None,
+    };
+    let mut file_watch = None;
+    if let (Some(inotify), Some(follower)) = (&inotify_option, &follower) {
+        // Watch before the initial read, so no change goes unnoticed
+        file_watch = Some(watch_followed_file(inotify, follower.path())?);
+    }
+
+    let source = if source_producer.is_stream() {
+        Source::streaming()
+    } else if let Some(follower) = &mut follower {
+        let lines = match follower
+            .read_update()
+            .with_context(|| format!("Failed to read file '{}'", follower.path()))?
+        {
+            FollowUpdate::Appended(lines)
+            | FollowUpdate::Truncated(lines)
+            | FollowUpdate::Rotated(lines) => lines,
+        };
+        Source::following(lines)
+    } else {
+        read_source(&source_producer)?
+    };
 
     // Setup ncurses
     ncurses::initscr();
@@ -73,49 +110,89 @@ pub fn igrepper(
     for (i, c) in MATCH_COLORS.iter().enumerate() {
         ncurses::init_pair(i as i16 + 1, *c, 232i16);
     }
+    for (i, c) in STAGE_MATCH_COLORS.iter().flatten().enumerate() {
+        ncurses::init_pair((MAX_MATCH_COLORS + i) as i16 + 1, *c, 232i16);
+    }
 
     ncurses::refresh();
 
     let (max_y, max_x) = get_screen_size();
 
-    let mut core = core::Core::new();
-    let mut state = state::State::new(
-        source,
-        vec![SearchLine::new(
-            String::from(initial_regex.unwrap_or("")),
-            initial_context,
-            false,
-            false,
-        )],
-        0,
-        0,
-        max_y,
-        max_x,
-    );
+    let records = !matches!(record_mode, RecordMode::Lines);
+    let mut core =
+        core::Core::with_record_mode(record_mode).with_filtered_context(filtered_context);
+    let output_separator = if source_producer.decoder.is_null_data() {
+        "\0"
+    } else {
+        "\n"
+    };
+    let mut state =
+        state::State::new_with_source(source, vec![initial_search_line], 0, 0, max_y, max_x);
     let (tx, rx) = mpsc::channel();
     let (char_requester_tx, char_requester_rx) = mpsc::channel();
 
-    if let Some(mut inotify) = inotify_option {
+    // Shared with the main loop, which reloads the followed file on F5
+    let follower = follower.map(|follower| Arc::new(Mutex::new(follower)));
+    if let (Some(inotify), Some(follower), Some(file_watch)) =
+        (inotify_option, &follower, file_watch)
+    {
         let inotify_tx = tx.clone();
+        let follower = Arc::clone(follower);
+        thread::spawn(move || follow_file(inotify, follower, file_watch, inotify_tx));
+    }
+    let reload_tx = tx.clone();
+
+    if let Some(stream) = source_producer.take_stream() {
+        let stream_tx = tx.clone();
+        let decoder = source_producer.decoder.clone();
         thread::spawn(move || {
-            let mut buffer = [0; 1024];
-
-            loop {
-                let events_result = inotify.read_events_blocking(&mut buffer);
-                match events_result {
-                    Ok(events) => {
-                        if events.count() > 0 {
-                            inotify_tx.send(Message::ReloadFile).unwrap();
-                        }
-                    }
-                    Err(e) => {
-                        inotify_tx.send(Message::Error(e.to_string())).unwrap();
-                    }
+            let result = file_reading::decompress(stream)
+                .and_then(|s| decoder.reader(s))
+                .and_then(|stream| {
+                    file_reading::read_lines_in_batches(BufReader::new(stream), &decoder, |lines| {
+                        // The receiver is gone once the UI has exited
+                        let _ = stream_tx.send(Message::AppendLines(lines));
+                    })
+                });
+            let _ = stream_tx.send(match result {
+                Ok(()) => Message::EndOfInput,
+                Err(e) => Message::Error(format!("Failed to read input: {}", e)),
+            });
+        });
+    }
+
+    // The input is read again on a thread of its own, so that a slow
+    // command does not keep the UI from responding
+    let source_producer = Arc::new(source_producer);
+    let (reload_request_tx, reload_request_rx) = mpsc::channel::<()>();
+    if source_producer.is_reloadable() {
+        let reloader_tx = tx.clone();
+        let source_producer = Arc::clone(&source_producer);
+        thread::spawn(move || {
+            while reload_request_rx.recv().is_ok() {
+                // Reloading can be slower than the timer asking for it
+                while reload_request_rx.try_recv().is_ok() {}
+                let message = match read_source(&source_producer) {
+                    Ok(source) => Message::Reloaded(source),
+                    Err(e) => Message::ReloadFailed(format!("{:#}", e)),
+                };
+                if reloader_tx.send(message).is_err() {
+                    break;
                 }
             }
         });
     }
 
+    if let Some(interval) = reload_interval {
+        let reload_request_tx = reload_request_tx.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if reload_request_tx.send(()).is_err() {
+                break;
+            }
+        });
+    }
+
     thread::spawn(move || {
         while let CharRequesterMessage::ReadyToReceiveChar = char_requester_rx
             .recv()
@@ -126,137 +203,330 @@ pub fn igrepper(
         }
     });
 
+    let mut waiting_for_char = false;
+    let mut pending_message: Option<Message> = None;
     loop {
-        let render_state = core.get_render_state(&state);
-        rendering::render(render_state);
-        ncurses::refresh();
-        char_requester_tx
-            .send(CharRequesterMessage::ReadyToReceiveChar)
-            .unwrap();
-        let message = rx.recv().unwrap();
+        if pending_message.is_none() {
+            if source_producer.decoder.decoded_lossily() {
+                state.source().mark_lossy();
+            }
+            let render_state = core.get_render_state(&state);
+            rendering::render(render_state);
+            ncurses::refresh();
+        }
+        if !waiting_for_char {
+            char_requester_tx
+                .send(CharRequesterMessage::ReadyToReceiveChar)
+                .unwrap();
+            waiting_for_char = true;
+        }
+        let message = pending_message.take().unwrap_or_else(|| rx.recv().unwrap());
         match message {
-            Message::ReloadFile => {
-                let source = source_producer.get_source().inspect_err(|_| {
-                    clear_screen();
-                    ncurses::endwin();
-                })?;
-                state = state.set_source_lines(source);
+            Message::AppendLines(lines) => {
+                state = state.append_source_lines(lines);
+                // Input can arrive faster than it is rendered, so take all
+                // batches already waiting before rendering again.
+                loop {
+                    match rx.try_recv() {
+                        Ok(Message::AppendLines(lines)) => {
+                            state = state.append_source_lines(lines);
+                        }
+                        Ok(other) => {
+                            pending_message = Some(other);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+            Message::ReplaceSource(lines) => {
+                state = state.set_followed_lines(lines);
                 core.clear_cache();
             }
+            Message::EndOfInput => {
+                state = state.finish_source();
+            }
+            Message::Reloaded(source) => {
+                state = state.set_source(source);
+                core.clear_cache();
+            }
+            Message::ReloadFailed(error) => {
+                // The lines read before are kept
+                state.source().set_reload_error(error);
+            }
             Message::Error(message) => {
-                panic!("Inotify error: {}", message);
+                clear_screen();
+                ncurses::endwin();
+                return Err(anyhow!(message));
             }
-            Message::Character(ch) => match ch {
-                KEY_LEFT => {
-                    state = {
-                        let widest = core.widest_line_seen_so_far(&state);
-                        state.page_x(-5, widest)
+            Message::Character(ch) => {
+                waiting_for_char = false;
+                match ch {
+                    KEY_LEFT => {
+                        state = {
+                            let widest = core.widest_line_seen_so_far(&state);
+                            state.page_x(-5, widest)
+                        }
                     }
-                }
-                KEY_RIGHT => {
-                    state = {
-                        let widest = core.widest_line_seen_so_far(&state);
-                        state.page_x(5, widest)
+                    KEY_RIGHT => {
+                        state = {
+                            let widest = core.widest_line_seen_so_far(&state);
+                            state.page_x(5, widest)
+                        }
                     }
-                }
-                KEY_UP => state = page_y(-1, state, &mut core),
-                KEY_DOWN => state = page_y(1, state, &mut core),
+                    KEY_UP => state = page_y(-1, state, &mut core),
+                    KEY_DOWN => state = page_y(1, state, &mut core),
 
-                3 => {
-                    clear_screen();
-                    ncurses::endwin();
-                    break;
-                }
-                KEY_PPAGE => {
-                    state = {
-                        let y = state.max_y() as i32;
-                        page_y(-y, state, &mut core)
+                    3 => {
+                        clear_screen();
+                        ncurses::endwin();
+                        break;
                     }
-                }
-                KEY_NPAGE => {
-                    state = {
-                        let y = state.max_y() as i32;
-                        page_y(y, state, &mut core)
+                    KEY_PPAGE => {
+                        state = {
+                            let y = state.max_y() as i32;
+                            page_y(-y, state, &mut core)
+                        }
                     }
-                }
-                CTRL_U => {
-                    state = {
-                        let y = state.max_y() as i32;
-                        page_y(-y / 2, state, &mut core)
+                    KEY_NPAGE => {
+                        state = {
+                            let y = state.max_y() as i32;
+                            page_y(y, state, &mut core)
+                        }
                     }
-                }
-                CTRL_D => {
-                    state = {
-                        let y = state.max_y() as i32;
-                        page_y(y / 2, state, &mut core)
+                    CTRL_U => {
+                        state = {
+                            let y = state.max_y() as i32;
+                            page_y(-y / 2, state, &mut core)
+                        }
                     }
-                }
-                CTRL_L | KEY_RESIZE => {
-                    let (max_y, max_x) = get_screen_size();
-                    state = state.set_max_yx(max_y, max_x);
-                    ncurses::refresh();
-                }
-                CTRL_R => {
-                    state = state.modify_context(-1);
-                }
-                CTRL_T => {
-                    state = state.modify_context(1);
-                }
-                CTRL_N | KEY_ENTER | 0xa => {
-                    state = state.accept_partial_match();
-                }
-                CTRL_P => {
-                    state = state.revert_partial_match();
-                }
-                CTRL_I => {
-                    state = state.toggle_case_sensitivity();
-                }
-                CTRL_V => {
-                    state = state.toggle_inverted();
-                }
-                CTRL_G => {
-                    if !state.regex_valid() || state.empty_search_lines() {
-                        continue;
+                    CTRL_D => {
+                        state = {
+                            let y = state.max_y() as i32;
+                            page_y(y / 2, state, &mut core)
+                        }
                     }
-                    clear_screen();
-                    ncurses::endwin();
-                    copy_grep_to_clipboard(&state.search_lines());
-                    break;
-                }
-                CTRL_E => {
-                    if !state.regex_valid() {
-                        continue;
+                    CTRL_L | KEY_RESIZE => {
+                        let (max_y, max_x) = get_screen_size();
+                        state = state.set_max_yx(max_y, max_x);
+                        ncurses::refresh();
                     }
-                    clear_screen();
-                    ncurses::endwin();
-                    copy_full_to_clipboard_from_string(&core.get_full_output_string(&state));
-                    break;
-                }
-                F1 | F1_2 => {
-                    if !state.regex_valid() {
-                        continue;
+                    F5 => {
+                        if let Some(follower) = &follower {
+                            // Sent while the follower is locked, so updates it
+                            // read before are applied first and replaced.
+                            let mut follower = follower.lock().unwrap();
+                            let message = match follower.reload() {
+                                Ok(lines) => Message::ReplaceSource(lines),
+                                Err(e) => Message::Error(format!(
+                                    "Failed to reload file '{}': {}",
+                                    follower.path(),
+                                    e
+                                )),
+                            };
+                            reload_tx.send(message).unwrap();
+                        } else if source_producer.is_reloadable() {
+                            let _ = reload_request_tx.send(());
+                        }
                     }
-                    clear_screen();
-                    ncurses::endwin();
-                    pipe_to_external_editor(external_editor, &core.get_full_output_string(&state));
-                    break;
-                }
-                CTRL_H | KEY_BACKSPACE | ALTERNATIVE_BACKSPACE => {
-                    state = state.pop_search_char();
-                    state = page_y(0, state, &mut core)
-                }
-                c => {
-                    if let Some(new_char) = char::from_u32(c as u32) {
-                        state = state.push_search_char(new_char);
+                    CTRL_R => {
+                        state = state.modify_context(-1);
+                    }
+                    CTRL_T => {
+                        state = state.modify_context(1);
+                    }
+                    F2 => {
+                        state = state.modify_context_before(-1);
+                    }
+                    F3 => {
+                        state = state.modify_context_before(1);
+                    }
+                    F6 => {
+                        state = state.modify_context_after(-1);
+                    }
+                    F7 => {
+                        state = state.modify_context_after(1);
+                    }
+                    CTRL_N | KEY_ENTER | 0xa => {
+                        state = state.accept_partial_match();
+                    }
+                    CTRL_P => {
+                        state = state.revert_partial_match();
+                    }
+                    CTRL_B => {
+                        state = state.branch();
+                    }
+                    CTRL_K => {
+                        state = state.cycle_display_mode();
+                    }
+                    CTRL_W => {
+                        state = state.toggle_line_numbers();
+                    }
+                    CTRL_Y => {
+                        state = state.cycle_color_mode();
+                    }
+                    CTRL_S => {
+                        state = state.toggle_unique_order();
+                    }
+                    CTRL_I => {
+                        state = state.cycle_case_mode();
+                    }
+                    CTRL_V => {
+                        state = state.toggle_inverted();
+                    }
+                    CTRL_F => {
+                        state = state.toggle_literal();
+                    }
+                    CTRL_X => {
+                        state = state.cycle_engine();
+                    }
+                    CTRL_O => {
+                        state = state.toggle_ranked();
+                    }
+                    CTRL_A => {
+                        state = state.toggle_query();
+                    }
+                    CTRL_G => {
+                        if !state.regex_valid() || state.empty_search_lines() {
+                            continue;
+                        }
+                        clear_screen();
+                        ncurses::endwin();
+                        copy_grep_to_clipboard(
+                            &state
+                                .branch_states()
+                                .iter()
+                                .map(|s| s.search_lines())
+                                .collect::<Vec<Vec<SearchLine>>>(),
+                            &ExportOptions {
+                                file_paths: source_producer.file_paths(),
+                                recursive: source_producer.is_recursive(),
+                                null_data: source_producer.decoder.is_null_data(),
+                                display_mode: state.display_mode(),
+                                line_numbers: state.line_numbers(),
+                                filtered_context,
+                                records,
+                            },
+                        );
+                        break;
+                    }
+                    CTRL_E => {
+                        if !state.regex_valid() {
+                            continue;
+                        }
+                        clear_screen();
+                        ncurses::endwin();
+                        copy_full_to_clipboard_from_string(
+                            &core.get_full_output(&state).join(output_separator),
+                        );
+                        break;
+                    }
+                    F1 | F1_2 => {
+                        if !state.regex_valid() {
+                            continue;
+                        }
+                        clear_screen();
+                        ncurses::endwin();
+                        pipe_to_external_editor(
+                            external_editor,
+                            &core.get_full_output(&state).join(output_separator),
+                        );
+                        break;
+                    }
+                    CTRL_H | KEY_BACKSPACE | ALTERNATIVE_BACKSPACE => {
+                        state = state.pop_search_char();
                         state = page_y(0, state, &mut core)
                     }
+                    c => {
+                        if let Some(new_char) = char::from_u32(c as u32) {
+                            state = state.push_search_char(new_char);
+                            state = page_y(0, state, &mut core)
+                        }
+                    }
                 }
-            },
+            }
         }
     }
     Ok(())
 }
 
+const FOLLOWED_FILE_WATCH_MASK: WatchMask = WatchMask::MODIFY
+    .union(WatchMask::CLOSE_WRITE)
+    .union(WatchMask::MOVE_SELF)
+    .union(WatchMask::DELETE_SELF);
+
+/// Watches a followed file, and the directory containing it to
+/// notice when the file is recreated after rotation.
+fn watch_followed_file(inotify: &Inotify, path: &str) -> Result<WatchDescriptor> {
+    let directory = match Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    inotify
+        .watches()
+        .add(directory, WatchMask::CREATE | WatchMask::MOVED_TO)
+        .context("Failed to add directory watch")?;
+    inotify
+        .watches()
+        .add(path, FOLLOWED_FILE_WATCH_MASK)
+        .context("Failed to add file watch")
+}
+
+/// Reads changes to a followed file as inotify reports them, and sends them
+/// to the main loop. Re-attaches the watch when the file has been rotated.
+fn follow_file(
+    mut inotify: Inotify,
+    follower: Arc<Mutex<FileFollower>>,
+    mut file_watch: WatchDescriptor,
+    tx: mpsc::Sender<Message>,
+) {
+    let path = follower.lock().unwrap().path().to_string();
+    let error =
+        |e: std::io::Error| Message::Error(format!("Failed to follow file '{}': {}", path, e));
+    let file_name = Path::new(&path).file_name().map(|n| n.to_owned());
+    let mut buffer = [0; 1024];
+    loop {
+        let changed = match inotify.read_events_blocking(&mut buffer) {
+            Ok(events) => events.into_iter().any(|event| {
+                event.wd == file_watch
+                    || (event
+                        .mask
+                        .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                        && event.name.map(|n| n.to_owned()) == file_name)
+            }),
+            Err(e) => {
+                let _ = tx.send(error(e));
+                return;
+            }
+        };
+        if !changed {
+            continue;
+        }
+        let mut follower = follower.lock().unwrap();
+        let message = match follower.read_update() {
+            Ok(FollowUpdate::Appended(lines)) if lines.is_empty() => continue,
+            Ok(FollowUpdate::Appended(lines)) => Message::AppendLines(lines),
+            Ok(FollowUpdate::Truncated(lines)) => Message::ReplaceSource(lines),
+            Ok(FollowUpdate::Rotated(lines)) => {
+                // The old watch is already gone if the file was deleted
+                let _ = inotify.watches().remove(file_watch.clone());
+                match inotify.watches().add(&path, FOLLOWED_FILE_WATCH_MASK) {
+                    Ok(watch) => file_watch = watch,
+                    Err(e) => {
+                        let _ = tx.send(error(e));
+                        return;
+                    }
+                }
+                Message::AppendLines(lines)
+            }
+            Err(e) => error(e),
+        };
+        if tx.send(message).is_err() {
+            return;
+        }
+    }
+}
+
 /// Tries to page vertically, may query more output lines.
 fn page_y(amount: i32, s: State, c: &mut Core) -> State {
     let wanted_ypage = cmp::max(0, s.pager_y() as i32 + amount) as u32;
@@ -279,26 +549,203 @@ fn page_y(amount: i32, s: State, c: &mut Core) -> State {
     s.page_y(amount, output_lines_count)
 }
 
-fn copy_grep_to_clipboard(search_lines: &[SearchLine]) {
-    let grep_line = construct_grep_line(search_lines);
+/// Reads the full source, keeping track of which file each line came from.
+fn read_source(source_producer: &SourceProducer) -> Result<Source> {
+    // Only invalid input of this read is reported for the new source
+    source_producer.decoder.reset_decoded_lossily();
+    if let Some(output) = source_producer.run_command()? {
+        Ok(Source::from_command(
+            output.stdout,
+            output.stderr,
+            output.status,
+        ))
+    } else if let Some((map, text_start)) = source_producer.map_file()? {
+        Ok(Source::mapped(
+            map,
+            text_start,
+            source_producer.decoder.is_null_data(),
+        ))
+    } else if source_producer.file_paths().is_empty() {
+        Ok(Source::complete(source_producer.get_source()?))
+    } else {
+        Ok(Source::from_files(source_producer.get_source_files()?))
+    }
+}
+
+/// What the `grep` command copied with ctrl-g is made for, besides the
+/// search lines.
+#[derive(Debug, Default)]
+struct ExportOptions {
+    /// The input files, passed to the first command.
+    file_paths: Vec<String>,
+    /// Whether `file_paths` are directories to search.
+    recursive: bool,
+    /// Whether lines are separated by NUL.
+    null_data: bool,
+    display_mode: DisplayMode,
+    line_numbers: bool,
+    /// Whether igrepper takes context from the output of the search line
+    /// before, see `grep_context_note`.
+    filtered_context: bool,
+    /// Whether igrepper matches multi-line records, see `RecordMode`.
+    records: bool,
+}
+
+fn copy_grep_to_clipboard(branches: &[Vec<SearchLine>], options: &ExportOptions) {
+    let grep_line = construct_union_grep_line(branches, options);
     copy_to_clipboard(&grep_line);
-    print_copied_to_clipboard(grep_line);
+    let notes: Vec<&str> = [
+        grep_context_note(branches, options),
+        grep_record_note(options),
+        grep_query_note(branches, options),
+    ]
+    .iter()
+    .flatten()
+    .copied()
+    .collect();
+    print_copied_to_clipboard(grep_line, &notes);
+}
+
+/// A note that the `grep` command prints whole lines, if igrepper shows only
+/// the matches of a query, see `construct_grep_line`.
+fn grep_query_note(branches: &[Vec<SearchLine>], options: &ExportOptions) -> Option<&'static str> {
+    let shows_matches = matches!(
+        options.display_mode,
+        DisplayMode::OnlyMatching | DisplayMode::UniqueMatches(_)
+    );
+    let last_is_query = branches.iter().any(|search_lines| {
+        search_lines
+            .iter()
+            .rfind(|l| !l.line.is_empty())
+            .is_some_and(|l| l.query)
+    });
+    if shows_matches && last_is_query {
+        Some("Note: grep cannot print only the matches of a query, so the lines matching it are printed instead.")
+    } else {
+        None
+    }
+}
+
+/// A note that the `grep` command matches single lines, if igrepper matches
+/// multi-line records, which grep has no equivalent for.
+fn grep_record_note(options: &ExportOptions) -> Option<&'static str> {
+    if options.records {
+        Some("Note: grep matches single lines, not the records igrepper shows with --record-start or --paragraph.")
+    } else {
+        None
+    }
+}
+
+/// A note on how the context printed by the `grep` command differs from
+/// igrepper's, if it does. Later commands in a pipeline take their context
+/// from the output of the command before, which igrepper only does with
+/// `filtered_context`.
+fn grep_context_note(
+    branches: &[Vec<SearchLine>],
+    options: &ExportOptions,
+) -> Option<&'static str> {
+    let display_mode = options.display_mode;
+    let later_context =
+        branches.iter().any(|search_lines| {
+            let search_lines: Vec<&SearchLine> =
+                search_lines.iter().filter(|l| !l.line.is_empty()).collect();
+            let last = search_lines.len().saturating_sub(1);
+            search_lines.iter().enumerate().skip(1).any(|(i, l)| {
+                !l.context.is_empty() && (i < last || display_mode == DisplayMode::Lines)
+            })
+        });
+    if later_context && !options.filtered_context {
+        Some("Note: unlike igrepper, grep takes the context of later commands from the output of the command before, not from the input. Run igrepper with --filtered-context to show the same context.")
+    } else {
+        None
+    }
+}
+
+/// Like `construct_grep_line`, for the branches of a union. The pipelines of
+/// the branches run one after the other, so unlike in igrepper, their lines
+/// are not merged into their original order, and lines in several branches
+/// are repeated. Unique matches or lines are counted with `uniq -c`.
+fn construct_union_grep_line(branches: &[Vec<SearchLine>], options: &ExportOptions) -> String {
+    let pipelines: Vec<String> = branches
+        .iter()
+        .map(|search_lines| construct_grep_line(search_lines, options))
+        .filter(|pipeline| !pipeline.is_empty())
+        .collect();
+    let pipeline = match pipelines.len() {
+        1 => pipelines[0].clone(),
+        _ => format!("{{ {}; }}", pipelines.join("; ")),
+    };
+    let z = if options.null_data { " -z" } else { "" };
+    match options.display_mode.unique_order() {
+        Some(UniqueOrder::Count) => {
+            format!("{} | sort{z} | uniq -c{z} | sort -rn{z}", pipeline, z = z)
+        }
+        Some(UniqueOrder::Alphabetical) => format!("{} | sort{z} | uniq -c{z}", pipeline, z = z),
+        None => pipeline,
+    }
 }
 
-fn construct_grep_line(search_lines: &[SearchLine]) -> String {
+/// Constructs a pipeline of grep commands equivalent to the search lines.
+/// Any `file_paths` are passed to the first command, which then prefixes
+/// each line with its file name. With `recursive`, the paths are directories
+/// to search, though unlike igrepper, grep does not skip ignored files.
+/// With `null_data`, every command reads and writes NUL-separated lines.
+/// Unless showing whole lines, the last command prints no context, and
+/// it only prints the matches if `display_mode` shows matches. Unique lines
+/// are counted without file names. With `line_numbers`, the first command
+/// prefixes lines with their line number, which a later command printing
+/// only the matches drops.
+fn construct_grep_line(search_lines: &[SearchLine], options: &ExportOptions) -> String {
+    let display_mode = options.display_mode;
+    let search_lines: Vec<&SearchLine> =
+        search_lines.iter().filter(|l| !l.line.is_empty()).collect();
     search_lines
         .iter()
-        .filter(|l| !l.line.is_empty())
-        .map(|l| {
+        .enumerate()
+        .map(|(i, l)| {
+            let with_files = i == 0 && !options.file_paths.is_empty();
+            let last = i == search_lines.len() - 1;
+            // A query is a pattern of lookaheads, which match no text to print
+            let only_matching = last
+                && !l.query
+                && matches!(
+                    display_mode,
+                    DisplayMode::OnlyMatching | DisplayMode::UniqueMatches(_)
+                );
+            let with_context = !(last && display_mode != DisplayMode::Lines);
+            let line_number = i == 0 && options.line_numbers && display_mode.unique_order().is_none();
+            let with_filename = if display_mode.unique_order().is_some() {
+                " -h"
+            } else {
+                " -H"
+            };
             format!(
-                "{grep}{context}{inverted} --perl-regexp '{regex}'",
+                "{grep}{null_data}{with_filename}{line_number}{only_matching}{context}{inverted} {matcher} '{regex}'{files}",
                 grep = grep_path(),
-                context = if l.context > 0 && !l.inverse {
-                    format!(" --context {}", l.context)
+                null_data = if options.null_data { " -z" } else { "" },
+                with_filename = match (with_files, options.recursive) {
+                    (true, true) => format!("{} -r", with_filename),
+                    (true, false) => String::from(with_filename),
+                    _ => String::from(""),
+                },
+                files = if with_files {
+                    options
+                        .file_paths
+                        .iter()
+                        .map(|f| format!(" '{}'", f.replace('\'', "'\\''")))
+                        .collect::<String>()
                 } else {
                     String::from("")
                 },
-                regex = l.line_with_sensitivity_prefix().replace('\'', "'\\''"),
+                line_number = if line_number { " -n" } else { "" },
+                only_matching = if only_matching { " -o" } else { "" },
+                context = if with_context {
+                    grep_context(l)
+                } else {
+                    String::from("")
+                },
+                matcher = grep_matcher(l),
+                regex = grep_pattern(l).replace('\'', "'\\''"),
                 inverted = if l.inverse { " -v" } else { "" }
             )
         })
@@ -306,6 +753,93 @@ fn construct_grep_line(search_lines: &[SearchLine]) -> String {
         .join(" | ")
 }
 
+/// The context options of grep, with `--context` if it is symmetric.
+fn grep_context(search_line: &SearchLine) -> String {
+    let context = search_line.context;
+    let option = |name: &str, lines: u32| {
+        if lines > 0 {
+            format!(" {} {}", name, lines)
+        } else {
+            String::from("")
+        }
+    };
+    if context.is_symmetric() {
+        option("--context", context.before)
+    } else {
+        option("-B", context.before) + &option("-A", context.after)
+    }
+}
+
+fn grep_matcher(search_line: &SearchLine) -> &'static str {
+    match (search_line.engine, search_line.literal) {
+        _ if search_line.query => "--perl-regexp",
+        (Engine::Fuzzy { .. }, _) | (_, false) => "--perl-regexp",
+        _ if search_line.is_case_sensitive() => "-F",
+        _ => "-F -i",
+    }
+}
+
+/// The pattern to pass to grep. A fuzzy search becomes a regex matching its
+/// characters in order, which grep cannot rank. A query becomes a lookahead
+/// for each term, so that the terms can match in any order.
+fn grep_pattern(search_line: &SearchLine) -> String {
+    match search_line.engine {
+        _ if search_line.query => {
+            let term_regex = |term: &str| {
+                let term_line = SearchLine {
+                    line: String::from(term),
+                    query: false,
+                    ..search_line.clone()
+                };
+                let body = match term_line.engine {
+                    Engine::Fuzzy { .. } => fuzzy_regex(term),
+                    _ if term_line.literal => regex::escape(term),
+                    _ => String::from(term),
+                };
+                if term_line.is_case_sensitive() {
+                    format!("(?:{})", body)
+                } else {
+                    format!("(?i:{})", body)
+                }
+            };
+            let mut pattern = String::from("^");
+            for term in search_line.line.split_whitespace() {
+                match term.strip_prefix('!') {
+                    Some("") => {}
+                    Some(excluded) => pattern.push_str(&format!("(?!.*{})", term_regex(excluded))),
+                    None => {
+                        let alternatives = split_top_level(term)
+                            .into_iter()
+                            .filter(|alternative| !alternative.is_empty())
+                            .map(term_regex)
+                            .collect::<Vec<String>>();
+                        if !alternatives.is_empty() {
+                            pattern.push_str(&format!("(?=.*(?:{}))", alternatives.join("|")));
+                        }
+                    }
+                }
+            }
+            pattern
+        }
+        Engine::Fuzzy { .. } => SearchLine {
+            line: fuzzy_regex(&search_line.line),
+            ..search_line.clone()
+        }
+        .line_with_sensitivity_prefix(),
+        _ if search_line.literal => search_line.line.clone(),
+        _ => search_line.line_with_sensitivity_prefix(),
+    }
+}
+
+/// A regex matching the characters of `pattern` in order, possibly with gaps.
+fn fuzzy_regex(pattern: &str) -> String {
+    pattern
+        .chars()
+        .map(|c| regex::escape(&c.to_string()))
+        .collect::<Vec<String>>()
+        .join(".*")
+}
+
 fn grep_path() -> String {
     "grep".to_string()
 }
@@ -353,10 +887,10 @@ fn pipe_to_external_editor(command_and_arguments: Vec<String>, string: &String)
 
 fn copy_full_to_clipboard_from_string(string_to_copy: &String) {
     copy_to_clipboard(string_to_copy);
-    print_copied_to_clipboard(string_to_copy.clone());
+    print_copied_to_clipboard(string_to_copy.clone(), &[]);
 }
 
-fn print_copied_to_clipboard(string: String) {
+fn print_copied_to_clipboard(string: String, notes: &[&str]) {
     macro_rules! copied_to_clipboard {
         () => {
             "Copied to clipboard: \n\n"
@@ -399,11 +933,15 @@ fn print_copied_to_clipboard(string: String) {
         ),
         string
     );
+    for note in notes {
+        println!("{}", note);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::igrepper::state::{CaseMode, Context};
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -411,7 +949,7 @@ mod tests {
         let search_lines: Vec<SearchLine> =
             vec![SearchLine::new("foo".to_string(), 0, false, false)];
         assert_eq!(
-            construct_grep_line(&search_lines),
+            construct_grep_line(&search_lines, &ExportOptions::default()),
             "grep --perl-regexp \'(?i)foo\'"
         );
     }
@@ -421,7 +959,7 @@ mod tests {
         let search_lines: Vec<SearchLine> =
             vec![SearchLine::new("foo".to_string(), 0, true, false)];
         assert_eq!(
-            construct_grep_line(&search_lines),
+            construct_grep_line(&search_lines, &ExportOptions::default()),
             "grep --perl-regexp \'foo\'"
         );
     }
@@ -431,7 +969,7 @@ mod tests {
         let search_lines: Vec<SearchLine> =
             vec![SearchLine::new("foo".to_string(), 0, false, true)];
         assert_eq!(
-            construct_grep_line(&search_lines),
+            construct_grep_line(&search_lines, &ExportOptions::default()),
             "grep -v --perl-regexp \'(?i)foo\'"
         );
     }
@@ -440,7 +978,7 @@ mod tests {
     fn construct_grep_sensitive_and_inverted() {
         let search_lines: Vec<SearchLine> = vec![SearchLine::new("foo".to_string(), 0, true, true)];
         assert_eq!(
-            construct_grep_line(&search_lines),
+            construct_grep_line(&search_lines, &ExportOptions::default()),
             "grep -v --perl-regexp \'foo\'"
         );
     }
@@ -450,18 +988,36 @@ mod tests {
         let search_lines: Vec<SearchLine> =
             vec![SearchLine::new("foo".to_string(), 2, false, false)];
         assert_eq!(
-            construct_grep_line(&search_lines),
+            construct_grep_line(&search_lines, &ExportOptions::default()),
             "grep --context 2 --perl-regexp \'(?i)foo\'"
         );
     }
 
     #[test]
-    fn construct_grep_context_is_ignored_when_inverted() {
+    fn construct_grep_asymmetric_context() {
+        let search_lines: Vec<SearchLine> = vec![
+            SearchLine {
+                context: Context::new(0, 2),
+                ..SearchLine::new("foo".to_string(), 0, true, false)
+            },
+            SearchLine {
+                context: Context::new(1, 3),
+                ..SearchLine::new("bar".to_string(), 0, true, false)
+            },
+        ];
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep -A 2 --perl-regexp \'foo\' | grep -B 1 -A 3 --perl-regexp \'bar\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_context_when_inverted() {
         let search_lines: Vec<SearchLine> =
             vec![SearchLine::new("foo".to_string(), 2, false, true)];
         assert_eq!(
-            construct_grep_line(&search_lines),
-            "grep -v --perl-regexp \'(?i)foo\'"
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep --context 2 -v --perl-regexp \'(?i)foo\'"
         );
     }
 
@@ -472,9 +1028,289 @@ mod tests {
             SearchLine::new("bar".to_string(), 1, true, false),
         ];
         assert_eq!(
-            construct_grep_line(&search_lines),
+            construct_grep_line(&search_lines, &ExportOptions::default()),
             "grep --perl-regexp \'(?i)foo\' | grep --context 1 --perl-regexp \'bar\'"
         );
+        let branches = [search_lines];
+        assert!(grep_context_note(&branches, &ExportOptions::default()).is_some());
+        assert!(grep_record_note(&ExportOptions::default()).is_none());
+        assert!(grep_record_note(&ExportOptions {
+            records: true,
+            ..ExportOptions::default()
+        })
+        .is_some());
+        assert!(grep_context_note(
+            &branches,
+            &ExportOptions {
+                filtered_context: true,
+                ..ExportOptions::default()
+            }
+        )
+        .is_none());
+        assert!(grep_context_note(
+            &branches,
+            &ExportOptions {
+                display_mode: DisplayMode::OnlyMatching,
+                ..ExportOptions::default()
+            }
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn construct_grep_multiple_files() {
+        let search_lines: Vec<SearchLine> = vec![
+            SearchLine::new("".to_string(), 0, false, false),
+            SearchLine::new("foo".to_string(), 0, false, false),
+            SearchLine::new("bar".to_string(), 0, true, false),
+        ];
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions { file_paths: vec!["app1.log".to_string(), "it's.log".to_string()], ..ExportOptions::default() }),
+            "grep -H --perl-regexp \'(?i)foo\' \'app1.log\' \'it\'\\\'\'s.log\' | grep --perl-regexp \'bar\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_recursive() {
+        let search_lines: Vec<SearchLine> =
+            vec![SearchLine::new("foo".to_string(), 1, false, true)];
+        assert_eq!(
+            construct_grep_line(
+                &search_lines,
+                &ExportOptions {
+                    file_paths: vec!["src/".to_string()],
+                    recursive: true,
+                    ..ExportOptions::default()
+                }
+            ),
+            "grep -H -r --context 1 -v --perl-regexp \'(?i)foo\' \'src/\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_literal() {
+        let mut search_lines: Vec<SearchLine> = vec![
+            SearchLine::new("a.b[0]".to_string(), 0, false, false),
+            SearchLine::new("(null)".to_string(), 0, true, false),
+        ];
+        search_lines.iter_mut().for_each(|l| l.literal = true);
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep -F -i \'a.b[0]\' | grep -F \'(null)\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_smart_case() {
+        let search_lines: Vec<SearchLine> = ["foo", "Bar", "\\S+"]
+            .iter()
+            .map(|l| SearchLine {
+                case_mode: CaseMode::Smart,
+                ..SearchLine::new(l.to_string(), 0, false, false)
+            })
+            .collect();
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep --perl-regexp \'(?i)foo\' | grep --perl-regexp \'Bar\' | grep --perl-regexp \'(?i)\\S+\'"
+        );
+        let literal = SearchLine {
+            literal: true,
+            ..search_lines[2].clone()
+        };
+        assert_eq!(
+            construct_grep_line(&[literal], &ExportOptions::default()),
+            "grep -F \'\\S+\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_fuzzy() {
+        let search_lines: Vec<SearchLine> = vec![SearchLine {
+            engine: Engine::Fuzzy { ranked: true },
+            literal: true,
+            ..SearchLine::new("a.b".to_string(), 0, false, false)
+        }];
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep --perl-regexp \'(?i)a.*\\..*b\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_query() {
+        let search_lines: Vec<SearchLine> = vec![SearchLine {
+            query: true,
+            ..SearchLine::new("foo|b.r !Baz qux ! ||".to_string(), 1, false, false)
+        }];
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep --context 1 --perl-regexp \'^(?=.*(?:(?i:foo)|(?i:b.r)))(?!.*(?i:Baz))(?=.*(?:(?i:qux)))\'"
+        );
+        let search_lines: Vec<SearchLine> = vec![SearchLine {
+            query: true,
+            literal: true,
+            case_mode: CaseMode::Smart,
+            ..SearchLine::new("a.b !C".to_string(), 0, false, true)
+        }];
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep -v --perl-regexp \'^(?=.*(?:(?i:a\\.b)))(?!.*(?:C))\'"
+        );
+        let search_lines: Vec<SearchLine> = vec![SearchLine {
+            query: true,
+            ..SearchLine::new("(a|b)c|x{1,2}".to_string(), 0, true, false)
+        }];
+        assert_eq!(
+            construct_grep_line(&search_lines, &ExportOptions::default()),
+            "grep --perl-regexp \'^(?=.*(?:(?:(a|b)c)|(?:x{1,2})))\'"
+        );
+
+        // Lookaheads match no text, so lines are printed instead of matches
+        let search_lines: Vec<SearchLine> = vec![SearchLine {
+            query: true,
+            ..SearchLine::new("foo".to_string(), 0, true, false)
+        }];
+        let options = ExportOptions {
+            display_mode: DisplayMode::OnlyMatching,
+            ..ExportOptions::default()
+        };
+        assert_eq!(
+            construct_grep_line(&search_lines, &options),
+            "grep --perl-regexp \'^(?=.*(?:(?:foo)))\'"
+        );
+        let branches = [search_lines];
+        assert!(grep_query_note(&branches, &options).is_some());
+        assert!(grep_query_note(&branches, &ExportOptions::default()).is_none());
+    }
+
+    #[test]
+    fn construct_grep_union() {
+        let branches: Vec<Vec<SearchLine>> = vec![
+            vec![
+                SearchLine::new("a".to_string(), 0, true, false),
+                SearchLine::new("b".to_string(), 0, true, false),
+            ],
+            vec![SearchLine::new("".to_string(), 0, true, false)],
+            vec![
+                SearchLine::new("a".to_string(), 0, true, false),
+                SearchLine::new("c".to_string(), 0, true, true),
+            ],
+        ];
+        assert_eq!(
+            construct_union_grep_line(
+                &branches,
+                &ExportOptions {
+                    file_paths: vec!["f".to_string()],
+                    ..ExportOptions::default()
+                }
+            ),
+            "{ grep -H --perl-regexp \'a\' \'f\' | grep --perl-regexp \'b\'; \
+             grep -H --perl-regexp \'a\' \'f\' | grep -v --perl-regexp \'c\'; }"
+        );
+        assert_eq!(
+            construct_union_grep_line(&branches[..1], &ExportOptions::default()),
+            "grep --perl-regexp \'a\' | grep --perl-regexp \'b\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_only_matching() {
+        let search_lines: Vec<SearchLine> = vec![
+            SearchLine::new("foo".to_string(), 2, true, false),
+            SearchLine::new("b.r".to_string(), 2, true, false),
+            SearchLine::new("".to_string(), 2, true, false),
+        ];
+        assert_eq!(
+            construct_grep_line(
+                &search_lines,
+                &ExportOptions {
+                    display_mode: DisplayMode::OnlyMatching,
+                    ..ExportOptions::default()
+                }
+            ),
+            "grep --context 2 --perl-regexp \'foo\' | grep -o --perl-regexp \'b.r\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_line_numbers() {
+        let search_lines: Vec<SearchLine> = vec![
+            SearchLine::new("foo".to_string(), 0, true, false),
+            SearchLine::new("bar".to_string(), 1, true, false),
+        ];
+        assert_eq!(
+            construct_grep_line(
+                &search_lines,
+                &ExportOptions {
+                    file_paths: vec!["a.log".to_string()],
+                    line_numbers: true,
+                    ..ExportOptions::default()
+                }
+            ),
+            "grep -H -n --perl-regexp \'foo\' \'a.log\' | grep --context 1 --perl-regexp \'bar\'"
+        );
+        assert_eq!(
+            construct_grep_line(
+                &search_lines,
+                &ExportOptions {
+                    display_mode: DisplayMode::UniqueLines(UniqueOrder::Count),
+                    line_numbers: true,
+                    ..ExportOptions::default()
+                }
+            ),
+            "grep --perl-regexp \'foo\' | grep --perl-regexp \'bar\'"
+        );
+    }
+
+    #[test]
+    fn construct_grep_unique() {
+        let branches: Vec<Vec<SearchLine>> = vec![vec![
+            SearchLine::new("error".to_string(), 0, true, false),
+            SearchLine::new("E[0-9]+".to_string(), 1, true, false),
+        ]];
+        assert_eq!(
+            construct_union_grep_line(
+                &branches,
+                &ExportOptions {
+                    file_paths: vec!["a.log".to_string()],
+                    display_mode: DisplayMode::UniqueMatches(UniqueOrder::Count),
+                    ..ExportOptions::default()
+                }
+            ),
+            "grep -h --perl-regexp \'error\' \'a.log\' | grep -o --perl-regexp \'E[0-9]+\' \
+             | sort | uniq -c | sort -rn"
+        );
+        assert_eq!(
+            construct_union_grep_line(
+                &branches,
+                &ExportOptions {
+                    null_data: true,
+                    display_mode: DisplayMode::UniqueLines(UniqueOrder::Alphabetical),
+                    ..ExportOptions::default()
+                }
+            ),
+            "grep -z --perl-regexp \'error\' | grep -z --perl-regexp \'E[0-9]+\' \
+             | sort -z | uniq -c -z"
+        );
+    }
+
+    #[test]
+    fn construct_grep_null_data() {
+        let search_lines: Vec<SearchLine> = vec![
+            SearchLine::new("foo".to_string(), 0, false, false),
+            SearchLine::new("bar".to_string(), 0, true, true),
+        ];
+        assert_eq!(
+            construct_grep_line(
+                &search_lines,
+                &ExportOptions {
+                    file_paths: vec!["a.log".to_string()],
+                    null_data: true,
+                    ..ExportOptions::default()
+                }
+            ),
+            "grep -z -H --perl-regexp \'(?i)foo\' \'a.log\' | grep -z -v --perl-regexp \'bar\'"
+        );
     }
 
     #[test]
@@ -482,7 +1318,7 @@ mod tests {
         let search_lines: Vec<SearchLine> =
             vec![SearchLine::new("isn't".to_string(), 0, false, false)];
         assert_eq!(
-            construct_grep_line(&search_lines),
+            construct_grep_line(&search_lines, &ExportOptions::default()),
             "grep --perl-regexp \'(?i)isn\'\\\'\'t\'"
         );
     }
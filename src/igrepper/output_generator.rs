@@ -1,8 +1,11 @@
+use crate::igrepper::matcher::{fuzzy_score, Matcher};
 pub(crate) use crate::igrepper::source_lines::SourceLines;
+use crate::igrepper::state::Context;
 use crate::igrepper::types::{Line, LineWithMatches, MatchPosition};
 use regex::Regex;
-use std::cmp;
-use std::collections::HashMap;
+use std::cmp::{self, Reverse};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
 
 #[derive(Debug)]
 pub enum Len {
@@ -10,31 +13,136 @@ pub enum Len {
     AtLeast(u32),
 }
 
+/// How input lines are grouped into records. A record is matched, shown
+/// and exported as a whole, and context is counted in records.
+#[derive(Debug, Clone, Default)]
+pub enum RecordMode {
+    /// Every line is a record of its own.
+    #[default]
+    Lines,
+    /// A record starts at each line matching the regex, e.g. the first line
+    /// of a log entry followed by a stack trace.
+    Start(Regex),
+    /// Records are separated by blank lines.
+    Paragraphs,
+}
+
+/// What is shown of the output lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DisplayMode {
+    /// Whole lines, with their context.
+    #[default]
+    Lines,
+    /// Only the matches, one per row, like `grep -o`.
+    OnlyMatching,
+    /// Each distinct match once, with the number of times it occurs.
+    UniqueMatches(UniqueOrder),
+    /// Each distinct matching line once, with the number of times it occurs.
+    UniqueLines(UniqueOrder),
+}
+
+/// The order of the rows of a unique view.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UniqueOrder {
+    /// Most frequent first, like `sort | uniq -c | sort -rn`.
+    #[default]
+    Count,
+    Alphabetical,
+}
+
+impl DisplayMode {
+    /// The mode following this one when cycling through them.
+    pub fn next(self) -> DisplayMode {
+        match self {
+            DisplayMode::Lines => DisplayMode::OnlyMatching,
+            DisplayMode::OnlyMatching => DisplayMode::UniqueMatches(UniqueOrder::default()),
+            DisplayMode::UniqueMatches(order) => DisplayMode::UniqueLines(order),
+            DisplayMode::UniqueLines(_) => DisplayMode::Lines,
+        }
+    }
+
+    /// Switches between the orders of a unique view.
+    pub fn toggle_order(self) -> DisplayMode {
+        let toggle = |order| match order {
+            UniqueOrder::Count => UniqueOrder::Alphabetical,
+            UniqueOrder::Alphabetical => UniqueOrder::Count,
+        };
+        match self {
+            DisplayMode::UniqueMatches(order) => DisplayMode::UniqueMatches(toggle(order)),
+            DisplayMode::UniqueLines(order) => DisplayMode::UniqueLines(toggle(order)),
+            display_mode => display_mode,
+        }
+    }
+
+    /// The order of the rows, if identical rows are collapsed into one.
+    pub fn unique_order(self) -> Option<UniqueOrder> {
+        match self {
+            DisplayMode::UniqueMatches(order) | DisplayMode::UniqueLines(order) => Some(order),
+            _ => None,
+        }
+    }
+}
+
 /// Struct representing the result of a regex search.
 /// It generates the output lazily.
 #[derive(Debug)]
 pub struct OutputGenerator {
     source_lines: SourceLines,
-    regex: Regex,
+    regex: Matcher,
     search_line_empty: bool,
-    context: u32,
+    context: Context,
     inverted: bool,
     result: Vec<Line>,
-    /// Tracks matching lines separately so a parent's output can be drained
-    /// into a child's buffered source by Core.
-    matching_lines: Vec<String>,
+    /// Tracks the source indices of matching lines separately so a parent's
+    /// output can be drained into a child's buffered source by Core.
+    matching_lines: Vec<usize>,
     lines_processed: u32,
     widest_line_seen: u32,
+    /// The lines in the output so far, keyed by their index in the original
+    /// input, so that context from the original input sorts between them.
     lines_with_match_ranges_dict: HashMap<usize, Line>,
+    record_mode: RecordMode,
+    /// Whether context lines are the neighbours in this stage's input,
+    /// instead of in the original input.
+    filtered_context: bool,
+    /// First lines of the records in the output, each shown after a separator.
+    record_starts: HashSet<usize>,
+    /// The lines just before each run of context, where a break line is
+    /// shown, unless the line joined the output with the context of another.
+    break_lines: HashSet<usize>,
+    /// The last records processed, kept for showing context before a match.
+    previous_records: VecDeque<Range<usize>>,
+    /// Number of records still to show as context after the last match.
+    after_context_remaining: u32,
+    /// The context of lines merged from the branches of a union, which is
+    /// that of their branch, keyed by source index. Other lines use `context`.
+    line_contexts: HashMap<usize, Context>,
+    /// How well each matching line matches, when the output is ranked.
+    /// Keyed like `lines_with_match_ranges_dict`.
+    scores: HashMap<usize, i64>,
+    display_mode: DisplayMode,
+    /// The rows of a unique view, keyed by their text, with how often each
+    /// occurs. Only the matching lines processed since are counted when the
+    /// output is mapped again.
+    unique_counts: HashMap<String, (usize, Line)>,
+    /// How many of `matching_lines` are counted in `unique_counts`.
+    unique_lines_counted: usize,
+    /// Whether `unique_counts` counts matches, instead of matching lines.
+    unique_counts_matches: bool,
+    /// The number of lines processed and in the output when `result` was
+    /// last mapped, to only map it again when either changed.
+    mapped_at: (u32, usize),
 }
 
 impl OutputGenerator {
     pub(crate) fn new(
         source_lines: SourceLines,
-        regex: Regex,
+        regex: Matcher,
         search_line_empty: bool,
-        context: u32,
+        context: Context,
         inverted: bool,
+        record_mode: RecordMode,
+        filtered_context: bool,
     ) -> OutputGenerator {
         OutputGenerator {
             source_lines,
@@ -47,6 +155,27 @@ impl OutputGenerator {
             widest_line_seen: 0,
             result: vec![],
             matching_lines: vec![],
+            record_mode,
+            filtered_context,
+            record_starts: HashSet::new(),
+            break_lines: HashSet::new(),
+            previous_records: VecDeque::new(),
+            after_context_remaining: 0,
+            line_contexts: HashMap::new(),
+            scores: HashMap::new(),
+            display_mode: DisplayMode::default(),
+            unique_counts: HashMap::new(),
+            unique_lines_counted: 0,
+            unique_counts_matches: false,
+            mapped_at: (0, 0),
+        }
+    }
+
+    /// Changes what is shown of the lines found so far, and from now on.
+    pub fn set_display_mode(&mut self, display_mode: DisplayMode) {
+        if self.display_mode != display_mode {
+            self.display_mode = display_mode;
+            self.map_to_vec();
         }
     }
 
@@ -71,41 +200,183 @@ impl OutputGenerator {
         &self.result
     }
 
-    pub fn full_string_vec(&mut self) -> Vec<String> {
-        self.full_vec()
+    /// All output lines as text. With `line_numbers`, lines start with their
+    /// position in the input, see `position_label`.
+    pub fn full_string_vec(&mut self, line_numbers: bool) -> Vec<String> {
+        self.request(u32::MAX);
+        self.result
             .iter()
-            .filter_map(|line| match line {
-                Line::LineWithMatches(l) => Some(l.line.clone()),
-                _ => None,
+            .filter_map(|line| {
+                let text = self.text(line)?;
+                Some(match line {
+                    Line::LineWithMatches(l) | Line::Match(l) if line_numbers => {
+                        format!("{}{}", self.position_label(l), text)
+                    }
+                    _ => text,
+                })
             })
             .collect::<Vec<String>>()
     }
 
-    pub fn full_string(&mut self) -> String {
-        self.full_string_vec().join("\n")
+    /// The text of an output line. Unique rows start with their count, like
+    /// the output of `uniq -c`.
+    fn text(&self, line: &Line) -> Option<String> {
+        match line {
+            Line::LineWithMatches(l) => self.line(l.source_index),
+            Line::Match(l) => self.matched_text(l),
+            Line::Unique { count, line } => {
+                self.text(line).map(|text| format!("{:>7} {}", count, text))
+            }
+            Line::BreakLine => None,
+        }
     }
 
-    pub fn slice(&mut self, start: u32, end: u32) -> &[Line] {
+    pub fn slice(&self, start: u32, end: u32) -> &[Line] {
         &self.result[start as usize..end as usize]
     }
 
     /// Maps the internal dictionary state into a Vec.
     fn map_to_vec(&mut self) {
+        self.mapped_at = self.processed_state();
+        self.result.clear();
+        if let Some(order) = self.display_mode.unique_order() {
+            self.count_unique_rows();
+            self.result = self.unique_rows(order);
+            return;
+        }
         let mut line_numbers: Vec<usize> = self
             .lines_with_match_ranges_dict
             .keys()
             .cloned()
             .collect::<Vec<usize>>();
-        line_numbers.sort();
-        self.result.clear();
+        line_numbers.extend(
+            self.break_lines
+                .iter()
+                .filter(|i| !self.lines_with_match_ranges_dict.contains_key(i)),
+        );
+        if self.regex.is_ranked() {
+            line_numbers.sort_by_key(|n| (Reverse(self.scores.get(n).copied().unwrap_or(0)), *n));
+        } else {
+            line_numbers.sort();
+        }
         for line_num in line_numbers {
-            self.result.push(
-                self.lines_with_match_ranges_dict
-                    .get(&line_num)
-                    .unwrap()
-                    .clone(),
-            );
+            let line = match self.lines_with_match_ranges_dict.get(&line_num) {
+                Some(line) => line,
+                None => {
+                    if self.display_mode != DisplayMode::OnlyMatching {
+                        self.result.push(Line::BreakLine);
+                    }
+                    continue;
+                }
+            };
+            if self.display_mode == DisplayMode::OnlyMatching {
+                if let Line::LineWithMatches(l) = line {
+                    for m in l.matches.iter().filter(|m| m.end > m.start) {
+                        self.result.push(Line::Match(LineWithMatches {
+                            source_index: l.source_index,
+                            matches: vec![m.clone()],
+                        }));
+                    }
+                }
+                continue;
+            }
+            if self.record_starts.contains(&line_num) && !self.result.is_empty() {
+                self.result.push(Line::BreakLine);
+            }
+            self.result.push(line.clone());
+        }
+    }
+
+    /// Counts the matches, or matching lines, processed since the last
+    /// call, starting over if the other of the two was counted.
+    fn count_unique_rows(&mut self) {
+        let count_matches = matches!(self.display_mode, DisplayMode::UniqueMatches(_));
+        if count_matches != self.unique_counts_matches {
+            self.unique_counts.clear();
+            self.unique_lines_counted = 0;
+            self.unique_counts_matches = count_matches;
+        }
+        for &line_num in &self.matching_lines[self.unique_lines_counted..] {
+            let l = match self.lines_with_match_ranges_dict.get(&line_num) {
+                Some(Line::LineWithMatches(l)) => l,
+                _ => continue,
+            };
+            let lines: Vec<Line> = if count_matches {
+                l.matches
+                    .iter()
+                    .filter(|m| m.end > m.start)
+                    .map(|m| {
+                        Line::Match(LineWithMatches {
+                            source_index: l.source_index,
+                            matches: vec![m.clone()],
+                        })
+                    })
+                    .collect()
+            } else if !l.matches.is_empty() {
+                vec![Line::LineWithMatches(l.clone())]
+            } else {
+                // Lines of a matching record without a match of their own
+                vec![]
+            };
+            for line in lines {
+                if let Some(text) = self.text(&line) {
+                    self.unique_counts.entry(text).or_insert((0, line)).0 += 1;
+                }
+            }
+        }
+        self.unique_lines_counted = self.matching_lines.len();
+    }
+
+    /// Collapses identical matches, or matching lines, into one row each.
+    fn unique_rows(&self, order: UniqueOrder) -> Vec<Line> {
+        let mut rows: Vec<(&String, &(usize, Line))> = self.unique_counts.iter().collect();
+        match order {
+            UniqueOrder::Count => rows.sort_by(|(a, (a_count, _)), (b, (b_count, _))| {
+                b_count.cmp(a_count).then_with(|| a.cmp(b))
+            }),
+            UniqueOrder::Alphabetical => rows.sort_by_key(|(a, _)| *a),
         }
+        rows.into_iter()
+            .map(|(_, (count, line))| Line::Unique {
+                count: *count,
+                line: Box::new(line.clone()),
+            })
+            .collect()
+    }
+
+    /// The text of the single match of a `Line::Match`.
+    pub fn matched_text(&self, line: &LineWithMatches) -> Option<String> {
+        let m = line.matches.first()?;
+        self.line(line.source_index)
+            .and_then(|text| text.get(m.start as usize..m.end as usize).map(String::from))
+    }
+
+    pub fn regex(&self) -> &Matcher {
+        &self.regex
+    }
+
+    pub fn inverted(&self) -> bool {
+        self.inverted
+    }
+
+    pub fn context(&self) -> Context {
+        self.context
+    }
+
+    /// Shows `context` around the line at `source_index`, which comes from a
+    /// branch with that context. Lines in several branches get the widest.
+    pub(crate) fn add_line_context(&mut self, source_index: usize, context: Context) {
+        let line_context = self.line_contexts.entry(source_index).or_default();
+        *line_context = line_context.widest(context);
+    }
+
+    /// The context around the line at `position` in the source lines.
+    fn context_at(&self, position: usize) -> Context {
+        let source_index = self.source_lines.source_index(position);
+        self.line_contexts
+            .get(&source_index)
+            .copied()
+            .unwrap_or(self.context)
     }
 
     pub fn widest_line_seen_so_far(&self) -> u32 {
@@ -116,114 +387,513 @@ impl OutputGenerator {
         self.source_lines.is_exhausted(self.lines_processed)
     }
 
-    pub fn matching_line_count(&self) -> usize {
-        self.matching_lines.len()
+    /// Whether processing has stopped because the source has no more lines yet.
+    pub fn is_waiting_for_source(&self) -> bool {
+        !self.is_fully_processed()
+            && match self.record_mode {
+                RecordMode::Lines => !self
+                    .source_lines
+                    .can_process(self.lines_processed, self.context.after),
+                _ => self.next_record().is_none(),
+            }
     }
 
-    pub fn matching_lines(&self) -> &[String] {
+    pub fn matching_lines(&self) -> &[usize] {
         &self.matching_lines
     }
 
-    pub(crate) fn source_lines_mut(&mut self) -> &mut SourceLines {
-        &mut self.source_lines
+    /// The number of lines at the start of the original input that have been
+    /// processed. Lines matching later come after these.
+    pub fn processed_source_line_count(&self) -> usize {
+        match self.lines_processed {
+            0 => 0,
+            n => self.source_lines.source_index(n as usize - 1) + 1,
+        }
     }
 
-    pub(crate) fn source_buffered_len(&self) -> usize {
-        self.source_lines.buffered_len()
+    /// The text of the line at `source_index` in the original input.
+    pub fn line(&self, source_index: usize) -> Option<String> {
+        self.source_lines.source().get(source_index)
     }
 
-    pub(crate) fn context(&self) -> u32 {
-        self.context
+    /// The name of the file the line at `source_index` was read from, and its
+    /// line number within that file, if the input consists of multiple files.
+    pub fn file_position(&self, source_index: usize) -> Option<(String, usize)> {
+        self.source_lines.source().file_position(source_index)
+    }
+
+    /// Whether the line at `source_index` was written to stderr by a command.
+    pub fn is_error_line(&self, source_index: usize) -> bool {
+        self.source_lines.source().is_error_line(source_index)
+    }
+
+    /// The line number of a line in the input, preceded by its file name if
+    /// the input consists of multiple files. Like the output of `grep -n`,
+    /// each part is followed by ':' for matching lines and '-' for context.
+    pub fn position_label(&self, line: &LineWithMatches) -> String {
+        let separator = if line.matches.is_empty() { '-' } else { ':' };
+        match self.file_position(line.source_index) {
+            Some((name, line_number)) => {
+                format!("{}{}{}{}", name, separator, line_number, separator)
+            }
+            None => format!("{}{}", line.source_index + 1, separator),
+        }
+    }
+
+    pub fn has_file_names(&self) -> bool {
+        self.source_lines.source().has_file_names()
+    }
+
+    pub(crate) fn source_lines_mut(&mut self) -> &mut SourceLines {
+        &mut self.source_lines
+    }
+
+    /// The index in the original input of the last line in the source.
+    pub(crate) fn last_buffered_source_index(&self) -> Option<usize> {
+        match self.source_lines.buffered_len() {
+            0 => None,
+            n => Some(self.source_lines.source_index(n - 1)),
+        }
     }
 
     /// Requests a number of output lines from the generator.
     /// Returns the number of lines calculated, either the same as requested, or less
     /// in case the end of the output was reached.
+    /// Ranked output can only be ordered once all lines are known, so all
+    /// available lines are processed. Ranking does not apply to records, and
+    /// ranked lines have no context. Unique rows are only known once all lines
+    /// are, too.
+    /// When only showing matches, lines without any are skipped, so more lines
+    /// may have to be processed.
     pub fn request(&mut self, requested: u32) -> Len {
         let request_chunk_size = 1000;
-        let end = requested
-            .saturating_sub(requested % request_chunk_size)
-            .saturating_add(request_chunk_size);
-        while self.lines_with_match_ranges_dict.len() < end as usize
-            && !self.source_lines.is_exhausted(self.lines_processed)
+        let mut end = if self.regex.is_ranked() || self.display_mode.unique_order().is_some() {
+            u32::MAX
+        } else {
+            requested
+                .saturating_sub(requested % request_chunk_size)
+                .saturating_add(request_chunk_size)
+        };
+        loop {
+            if matches!(self.record_mode, RecordMode::Lines) {
+                self.process_lines(end as usize);
+            } else {
+                self.process_records(end as usize);
+            }
+            if self.processed_state() != self.mapped_at {
+                self.map_to_vec();
+            }
+            if self.result.len() as u32 >= requested
+                || self.is_fully_processed()
+                || self.is_waiting_for_source()
+            {
+                return self.len();
+            }
+            end = end.saturating_mul(2);
+        }
+    }
+
+    /// The number of lines processed, and of lines in the output. Lines
+    /// only change in the output when one of them does.
+    fn processed_state(&self) -> (u32, usize) {
+        (
+            self.lines_processed,
+            self.lines_with_match_ranges_dict.len(),
+        )
+    }
+
+    /// Processes lines until `end` lines are in the output, or no more lines
+    /// can be processed.
+    fn process_lines(&mut self, end: usize) {
+        while self.lines_with_match_ranges_dict.len() < end
+            && self
+                .source_lines
+                .can_process(self.lines_processed, self.context.after)
         {
-            let line = match self.source_lines.get(self.lines_processed as usize) {
+            let line_match_ranges = match self.find_matches(self.lines_processed as usize) {
                 Some(l) => l,
                 None => break,
             };
-            self.widest_line_seen = cmp::max(self.widest_line_seen, line.len() as u32);
-
-            let line_match_ranges: Vec<MatchPosition> = self
-                .regex
-                .find_iter(&line)
-                .map(|match_on_line| MatchPosition {
-                    start: match_on_line.start() as u32,
-                    end: match_on_line.end() as u32,
-                })
-                .collect();
+            let source_index = self
+                .source_lines
+                .source_index(self.lines_processed as usize);
 
             if self.inverted && (self.search_line_empty || line_match_ranges.is_empty()) {
-                self.matching_lines.push(line.clone());
+                self.matching_lines.push(source_index);
                 self.lines_with_match_ranges_dict.insert(
-                    self.lines_processed as usize,
+                    source_index,
                     Line::LineWithMatches(LineWithMatches {
-                        line: line.clone(),
-                        matches: vec![MatchPosition { start: 0, end: 0 }],
+                        source_index,
+                        matches: vec![MatchPosition {
+                            start: 0,
+                            end: 0,
+                            color_group: None,
+                            stage: 0,
+                        }],
                     }),
                 );
+                self.add_context_lines();
             } else if !self.inverted && !line_match_ranges.is_empty() {
-                self.matching_lines.push(line.clone());
+                self.matching_lines.push(source_index);
+                if self.regex.is_ranked() {
+                    let score = self.score(&line_match_ranges);
+                    self.scores.insert(source_index, score);
+                }
                 self.lines_with_match_ranges_dict.insert(
-                    self.lines_processed as usize,
+                    source_index,
                     Line::LineWithMatches(LineWithMatches {
-                        line: line.clone(),
+                        source_index,
                         matches: line_match_ranges,
                     }),
                 );
-                let context_lines = self.get_context_lines();
-                for key in context_lines.keys() {
-                    self.lines_with_match_ranges_dict
-                        .insert(*key, context_lines[key].clone());
+                if !self.regex.is_ranked() {
+                    self.add_context_lines();
                 }
             }
             self.lines_processed += 1;
         }
-        self.map_to_vec();
-        self.len()
     }
 
-    fn get_context_lines(&self) -> HashMap<usize, Line> {
-        let mut context_lines: HashMap<usize, Line> = HashMap::new();
-        if self.context > 0 {
-            let first_context_line_num = self.lines_processed.saturating_sub(self.context) as usize;
-            let source_len = self.source_lines.available_count(self.lines_processed) as usize;
-            let last_context_line_num = cmp::min(
-                source_len,
-                self.lines_processed as usize + self.context as usize + 1,
-            );
-            // Add break-line
-            let break_line_num = first_context_line_num.saturating_sub(1);
-            if !self
-                .lines_with_match_ranges_dict
-                .contains_key(&break_line_num)
-            {
-                context_lines.insert(break_line_num, Line::BreakLine);
+    /// Finds the matches on the line at `position`.
+    fn find_matches(&mut self, position: usize) -> Option<Vec<MatchPosition>> {
+        let regex = &self.regex;
+        let (line_length, line_match_ranges) = self
+            .source_lines
+            .with_line(position, |line| (line.len(), regex.find_matches(line)))?;
+        self.widest_line_seen = cmp::max(self.widest_line_seen, line_length as u32);
+        Some(line_match_ranges)
+    }
+
+    /// Rates the matches on the line being processed, for ranked output.
+    fn score(&self, matches: &[MatchPosition]) -> i64 {
+        let ranges: Vec<Range<usize>> = matches
+            .iter()
+            .map(|m| m.start as usize..m.end as usize)
+            .collect();
+        self.source_lines
+            .with_line(self.lines_processed as usize, |line| {
+                fuzzy_score(line, &ranges)
+            })
+            .unwrap_or(0)
+    }
+
+    /// Like the line by line processing in `request`, but for multi-line records.
+    fn process_records(&mut self, end: usize) {
+        while self.lines_with_match_ranges_dict.len() < end {
+            let record = match self.next_record() {
+                Some(record) => record,
+                None => break,
+            };
+            if record.is_empty() {
+                // Only separator lines
+                self.lines_processed = record.end as u32;
+                continue;
+            }
+            let record_matches: Vec<Vec<MatchPosition>> = record
+                .clone()
+                .map(|position| self.find_matches(position).unwrap_or_default())
+                .collect();
+            let has_match = record_matches.iter().any(|m| !m.is_empty());
+
+            let selected = if self.inverted {
+                self.search_line_empty || !has_match
+            } else {
+                has_match
+            };
+            if selected {
+                let context = self.context_at(record.start);
+                // Like context lines, context records of a later stage are
+                // the neighbours in the original input, see `get_context_lines`
+                let original_context = !self.filtered_context
+                    && matches!(self.source_lines, SourceLines::Buffered { .. });
+                if original_context {
+                    let start = self.source_lines.source_index(record.start);
+                    for context_record in self.source_records_before(start, context.before) {
+                        self.add_context_source_record(context_record);
+                    }
+                } else {
+                    let previous_records = self.previous_records.len();
+                    for previous_record in self
+                        .previous_records
+                        .clone()
+                        .into_iter()
+                        .skip(previous_records.saturating_sub(context.before as usize))
+                    {
+                        self.add_context_record(previous_record);
+                    }
+                }
+                let record_matches = if self.inverted {
+                    vec![
+                        vec![MatchPosition {
+                            start: 0,
+                            end: 0,
+                            color_group: None,
+                            stage: 0,
+                        }];
+                        record.len()
+                    ]
+                } else {
+                    record_matches
+                };
+                self.add_matching_record(record.clone(), record_matches);
+                if original_context {
+                    let end = self.source_lines.source_index(record.end - 1) + 1;
+                    for context_record in self.source_records_after(end, context.after) {
+                        self.add_context_source_record(context_record);
+                    }
+                } else {
+                    self.after_context_remaining = context.after;
+                }
+            } else if self.after_context_remaining > 0 {
+                self.add_context_record(record.clone());
+                self.after_context_remaining -= 1;
             }
-            let unpopulated_context_line_numbers = (first_context_line_num..last_context_line_num)
-                .filter(|i| !self.lines_with_match_ranges_dict.contains_key(i));
 
-            for context_line_num in unpopulated_context_line_numbers {
-                if let Some(ctx_line) = self.source_lines.get(context_line_num) {
-                    context_lines.insert(
-                        context_line_num,
+            if self.context.before > 0 {
+                if self.previous_records.len() == self.context.before as usize {
+                    self.previous_records.pop_front();
+                }
+                self.previous_records.push_back(record.clone());
+            }
+            self.lines_processed = record.end as u32;
+        }
+    }
+
+    fn add_matching_record(
+        &mut self,
+        record: Range<usize>,
+        record_matches: Vec<Vec<MatchPosition>>,
+    ) {
+        for position in record.clone() {
+            self.matching_lines
+                .push(self.source_lines.source_index(position));
+        }
+        self.add_record(record, record_matches);
+    }
+
+    fn add_context_record(&mut self, record: Range<usize>) {
+        if !self
+            .record_starts
+            .contains(&self.source_lines.source_index(record.start))
+        {
+            let record_matches = vec![vec![]; record.len()];
+            self.add_record(record, record_matches);
+        }
+    }
+
+    /// Like `add_context_record`, for a record given by its lines in the original input.
+    fn add_context_source_record(&mut self, record: Range<usize>) {
+        if self.record_starts.insert(record.start) {
+            for source_index in record {
+                self.lines_with_match_ranges_dict
+                    .entry(source_index)
+                    .or_insert_with(|| {
                         Line::LineWithMatches(LineWithMatches {
-                            line: ctx_line,
+                            source_index,
                             matches: vec![],
-                        }),
-                    );
+                        })
+                    });
+            }
+        }
+    }
+
+    /// Up to `count` records of the original input that end before line
+    /// `start`, the closest first.
+    fn source_records_before(&self, start: usize, count: u32) -> Vec<Range<usize>> {
+        let mut records = vec![];
+        let mut end = start;
+        while records.len() < count as usize {
+            while end > 0 && self.is_source_record_separator(end - 1) {
+                end -= 1;
+            }
+            if end == 0 {
+                break;
+            }
+            let mut record_start = end - 1;
+            while record_start > 0 && !self.starts_source_record(record_start) {
+                record_start -= 1;
+            }
+            records.push(record_start..end);
+            end = record_start;
+        }
+        records
+    }
+
+    /// Up to `count` records of the original input that start at or after
+    /// line `end`. Records whose end has not been read yet are left out.
+    fn source_records_after(&self, end: usize, count: u32) -> Vec<Range<usize>> {
+        let source = self.source_lines.source();
+        let available = source.len();
+        let mut records = vec![];
+        let mut start = end;
+        while records.len() < count as usize {
+            while start < available && self.is_source_record_separator(start) {
+                start += 1;
+            }
+            if start >= available {
+                break;
+            }
+            let mut record_end = start + 1;
+            while record_end < available && !self.starts_source_record(record_end) {
+                record_end += 1;
+            }
+            if record_end == available && !source.is_complete() {
+                break;
+            }
+            records.push(start..record_end);
+            start = record_end;
+        }
+        records
+    }
+
+    /// Like `starts_record`, for the line at `source_index` in the original input.
+    fn starts_source_record(&self, source_index: usize) -> bool {
+        let source = self.source_lines.source();
+        match &self.record_mode {
+            RecordMode::Lines => true,
+            RecordMode::Start(record_start) => source
+                .with_line(source_index, |line| record_start.is_match(line))
+                .unwrap_or(false),
+            RecordMode::Paragraphs => {
+                self.is_source_record_separator(source_index)
+                    || self.is_source_record_separator(source_index - 1)
+            }
+        }
+    }
+
+    /// Like `is_record_separator`, for the line at `source_index` in the original input.
+    fn is_source_record_separator(&self, source_index: usize) -> bool {
+        matches!(self.record_mode, RecordMode::Paragraphs)
+            && self
+                .source_lines
+                .source()
+                .with_line(source_index, |line| line.trim().is_empty())
+                .unwrap_or(false)
+    }
+
+    fn add_record(&mut self, record: Range<usize>, record_matches: Vec<Vec<MatchPosition>>) {
+        self.record_starts
+            .insert(self.source_lines.source_index(record.start));
+        for (position, matches) in record.zip(record_matches) {
+            let source_index = self.source_lines.source_index(position);
+            self.lines_with_match_ranges_dict.insert(
+                source_index,
+                Line::LineWithMatches(LineWithMatches {
+                    source_index,
+                    matches,
+                }),
+            );
+        }
+    }
+
+    /// Finds the record at `lines_processed`, after any separator lines.
+    /// Returns None if where it ends is not known yet. The range is empty
+    /// if only separator lines could be skipped so far.
+    fn next_record(&self) -> Option<Range<usize>> {
+        let available = self.source_lines.buffered_len();
+        let processed = self.lines_processed as usize;
+        let mut start = processed;
+        while start < available && self.is_record_separator(start) {
+            start += 1;
+        }
+        let mut end = cmp::min(start + 1, available);
+        while end < available && !self.starts_record(end) {
+            end += 1;
+        }
+        if start < available && (end < available || self.source_lines.is_complete()) {
+            Some(start..end)
+        } else if start > processed {
+            Some(start..start)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a record starts at `position`, given that the line before it
+    /// belongs to a record. Lines that were not next to each other in the
+    /// original input always belong to different records.
+    fn starts_record(&self, position: usize) -> bool {
+        if self.source_lines.source_index(position)
+            != self.source_lines.source_index(position - 1) + 1
+        {
+            return true;
+        }
+        match &self.record_mode {
+            RecordMode::Lines => true,
+            RecordMode::Start(record_start) => self
+                .source_lines
+                .with_line(position, |line| record_start.is_match(line))
+                .unwrap_or(false),
+            RecordMode::Paragraphs => self.is_record_separator(position),
+        }
+    }
+
+    /// Whether the line at `position` separates records without being part of one.
+    fn is_record_separator(&self, position: usize) -> bool {
+        matches!(self.record_mode, RecordMode::Paragraphs)
+            && self
+                .source_lines
+                .with_line(position, |line| line.trim().is_empty())
+                .unwrap_or(false)
+    }
+
+    /// Adds the context around the line being processed, which is in the output.
+    fn add_context_lines(&mut self) {
+        let (break_line_index, context_lines) = self.get_context_lines();
+        if let Some(break_line_index) = break_line_index {
+            self.break_lines.insert(break_line_index);
+        }
+        for (key, line) in context_lines {
+            self.lines_with_match_ranges_dict.insert(key, line);
+        }
+    }
+
+    /// The context around the line being processed, and the line before it
+    /// to show a break line at, keyed like `lines_with_match_ranges_dict`. Unless
+    /// `filtered_context` is set, the context of a line in a stage after the
+    /// first are its neighbours in the original input, not in the output of
+    /// the stage before.
+    fn get_context_lines(&self) -> (Option<usize>, HashMap<usize, Line>) {
+        let mut context_lines: HashMap<usize, Line> = HashMap::new();
+        let mut break_line_index = None;
+        let context = self.context_at(self.lines_processed as usize);
+        if !context.is_empty() {
+            let (line_num, source_len) = if self.filtered_context {
+                (
+                    self.lines_processed as usize,
+                    self.source_lines.buffered_len(),
+                )
+            } else {
+                (
+                    self.source_lines
+                        .source_index(self.lines_processed as usize),
+                    self.source_lines.source().len(),
+                )
+            };
+            let source_index = |line_num: usize| {
+                if self.filtered_context {
+                    self.source_lines.source_index(line_num)
+                } else {
+                    line_num
                 }
+            };
+            let first_context_line_num = line_num.saturating_sub(context.before as usize);
+            let last_context_line_num = cmp::min(source_len, line_num + context.after as usize + 1);
+            break_line_index = Some(source_index(first_context_line_num.saturating_sub(1)));
+            let unpopulated_context_line_indices = (first_context_line_num..last_context_line_num)
+                .map(source_index)
+                .filter(|i| !self.lines_with_match_ranges_dict.contains_key(i));
+
+            for context_line_index in unpopulated_context_line_indices {
+                context_lines.insert(
+                    context_line_index,
+                    Line::LineWithMatches(LineWithMatches {
+                        source_index: context_line_index,
+                        matches: vec![],
+                    }),
+                );
             }
         }
-        context_lines
+        (break_line_index, context_lines)
     }
 }
@@ -1,10 +1,12 @@
 extern crate ncurses;
 
 use crate::igrepper::constants::*;
+use crate::igrepper::matcher::Engine;
+use crate::igrepper::state::CaseMode;
 use crate::igrepper::types::{RenderState, StringWithColorIndex, StringWithColorIndexOrBreakLine};
 use ncurses::{
     box_, chtype, getmaxyx, mvaddstr, mvwaddstr, mvwhline, newwin, stdscr, wattroff, wattron,
-    wbkgd, wrefresh, A_BOLD, A_REVERSE, COLOR_PAIR,
+    wbkgd, wrefresh, A_BOLD, A_REVERSE, A_UNDERLINE, COLOR_PAIR,
 };
 
 pub fn clear_screen() {
@@ -42,7 +44,21 @@ pub fn render(render_state: RenderState) {
     box_(input_window, 0, 0);
     wattroff(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
 
+    for (i, branch_line) in render_state.output_branch_lines.iter().enumerate() {
+        wattron(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+        mvwaddstr(input_window, i as i32 + 1, 1, BRANCH_PREFIX);
+        mvwaddstr(
+            input_window,
+            i as i32 + 1,
+            1 + BRANCH_PREFIX.len() as i32,
+            branch_line,
+        );
+        wattroff(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+    }
+    let branch_lines = render_state.output_branch_lines.len();
+    let last_search_line = branch_lines + render_state.output_search_lines.len() - 1;
     for (i, search_line) in render_state.output_search_lines.iter().enumerate() {
+        let i = i + branch_lines;
         let mut line: &str = search_line.line.as_str();
         let mut x_start = 1i32;
         if search_line.inverse {
@@ -51,6 +67,36 @@ pub fn render(render_state: RenderState) {
             wattroff(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
             x_start += 1;
         }
+        if search_line.query {
+            wattron(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            mvwaddstr(input_window, i as i32 + 1, x_start, QUERY_PREFIX);
+            wattroff(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            x_start += QUERY_PREFIX.len() as i32;
+        }
+        if search_line.literal {
+            wattron(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            mvwaddstr(input_window, i as i32 + 1, x_start, LITERAL_PREFIX);
+            wattroff(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            x_start += LITERAL_PREFIX.len() as i32;
+        }
+        let engine_prefix = match search_line.engine {
+            Engine::Standard => None,
+            Engine::Fancy => Some(FANCY_ENGINE_PREFIX),
+            Engine::Fuzzy { ranked: false } => Some(FUZZY_PREFIX),
+            Engine::Fuzzy { ranked: true } => Some(FUZZY_RANKED_PREFIX),
+        };
+        if let Some(engine_prefix) = engine_prefix {
+            wattron(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            mvwaddstr(input_window, i as i32 + 1, x_start, engine_prefix);
+            wattroff(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            x_start += engine_prefix.len() as i32;
+        }
+        if search_line.case_mode == CaseMode::Smart {
+            wattron(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            mvwaddstr(input_window, i as i32 + 1, x_start, SMART_CASE_PREFIX);
+            wattroff(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+            x_start += SMART_CASE_PREFIX.len() as i32;
+        }
 
         if line.starts_with(CASE_INSENSITIVE_PREFIX) {
             wattron(input_window, COLOR_PAIR(COLOR_PAIR_BORDER));
@@ -59,7 +105,7 @@ pub fn render(render_state: RenderState) {
             x_start += CASE_INSENSITIVE_PREFIX.len() as i32;
             line = &line[4..line.len()];
         }
-        if i == render_state.output_search_lines.len() - 1 {
+        if i == last_search_line {
             if render_state.regex_valid {
                 wattron(input_window, A_BOLD());
             } else {
@@ -73,7 +119,7 @@ pub fn render(render_state: RenderState) {
         }
 
         mvwaddstr(input_window, i as i32 + 1, x_start, line);
-        if i == render_state.output_search_lines.len() - 1 {
+        if i == last_search_line {
             if render_state.regex_valid {
                 wattroff(input_window, A_BOLD());
             } else {
@@ -113,11 +159,29 @@ pub fn render(render_state: RenderState) {
                             mvwaddstr(pager_window, i as i32 + 1, xpos, s);
                             xpos += s.len() as i32;
                         }
+                        StringWithColorIndex::Gutter(s) => {
+                            wattron(pager_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+                            mvwaddstr(pager_window, i as i32 + 1, xpos, s);
+                            wattroff(pager_window, COLOR_PAIR(COLOR_PAIR_BORDER));
+                            xpos += s.len() as i32;
+                        }
+                        StringWithColorIndex::ErrorString(s) => {
+                            wattron(pager_window, COLOR_PAIR(COLOR_PAIR_RED));
+                            mvwaddstr(pager_window, i as i32 + 1, xpos, s);
+                            wattroff(pager_window, COLOR_PAIR(COLOR_PAIR_RED));
+                            xpos += s.len() as i32;
+                        }
                         StringWithColorIndex::MatchString(s) => {
+                            // Matches of earlier search lines are underlined
+                            let attributes = if s.1 as usize >= MAX_MATCH_COLORS {
+                                A_UNDERLINE()
+                            } else {
+                                0
+                            };
                             wattron(input_window, A_BOLD());
-                            wattron(pager_window, COLOR_PAIR(s.1 as i16 + 1));
+                            wattron(pager_window, COLOR_PAIR(s.1 as i16 + 1) | attributes);
                             mvwaddstr(pager_window, i as i32 + 1, xpos, &s.0);
-                            wattroff(pager_window, COLOR_PAIR(s.1 as i16 + 1));
+                            wattroff(pager_window, COLOR_PAIR(s.1 as i16 + 1) | attributes);
                             wattroff(input_window, A_BOLD());
                             xpos += s.0.len() as i32;
                         }
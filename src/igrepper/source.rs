@@ -0,0 +1,327 @@
+use memmap2::Mmap;
+use std::borrow::Cow;
+use std::fmt;
+use std::process::ExitStatus;
+use std::sync::{Arc, RwLock};
+
+/// The input lines, shared between `State` and the `OutputGenerator`s reading them.
+/// Lines can be appended while the input is still being read, which lets
+/// existing output grow instead of being recalculated.
+#[derive(Clone)]
+pub struct Source {
+    inner: Arc<RwLock<SourceData>>,
+}
+
+struct SourceData {
+    lines: Lines,
+    complete: bool,
+    /// Whether some input was invalid in its encoding, and replaced.
+    lossy: bool,
+    /// The files the lines were read from, ordered by their first line.
+    /// Empty when the input did not come from multiple files.
+    files: Vec<FileStart>,
+    /// Index of the first line a command wrote to stderr. Those lines
+    /// follow the lines it wrote to stdout.
+    first_error_line: Option<usize>,
+    /// How the command producing the lines exited.
+    exit_status: Option<ExitStatus>,
+    /// Why reading the input again failed. The lines are the ones read before.
+    reload_error: Option<String>,
+}
+
+enum Lines {
+    Owned(Vec<String>),
+    /// A memory-mapped file, which is only decoded when a line is accessed.
+    Mapped {
+        map: Mmap,
+        /// Byte offset of the start of each line, followed by one past the
+        /// end of the last line, as if it ended with a terminator.
+        line_starts: Vec<usize>,
+        /// Whether lines end with NUL rather than a newline.
+        null_data: bool,
+    },
+}
+
+impl Lines {
+    fn len(&self) -> usize {
+        match self {
+            Lines::Owned(lines) => lines.len(),
+            Lines::Mapped { line_starts, .. } => line_starts.len() - 1,
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<Cow<'_, str>> {
+        match self {
+            Lines::Owned(lines) => lines.get(index).map(|line| Cow::Borrowed(line.as_str())),
+            Lines::Mapped {
+                map,
+                line_starts,
+                null_data,
+            } => {
+                let end = *line_starts.get(index + 1)? - 1;
+                let mut line = &map[line_starts[index]..end];
+                if !null_data && line.last() == Some(&b'\r') {
+                    line = &line[..line.len() - 1];
+                }
+                Some(String::from_utf8_lossy(line))
+            }
+        }
+    }
+}
+
+struct FileStart {
+    name: String,
+    first_line: usize,
+}
+
+impl Source {
+    fn new(lines: Lines, complete: bool, files: Vec<FileStart>) -> Source {
+        Source {
+            inner: Arc::new(RwLock::new(SourceData {
+                lines,
+                complete,
+                lossy: false,
+                files,
+                first_error_line: None,
+                exit_status: None,
+                reload_error: None,
+            })),
+        }
+    }
+
+    /// A source where all input is already known.
+    pub fn complete(lines: Vec<String>) -> Source {
+        Source::new(Lines::Owned(lines), true, vec![])
+    }
+
+    /// A complete source made up of the lines of several files, in order.
+    pub fn from_files(files: Vec<(String, Vec<String>)>) -> Source {
+        let mut lines: Vec<String> = vec![];
+        let mut file_starts: Vec<FileStart> = vec![];
+        for (name, file_lines) in files {
+            file_starts.push(FileStart {
+                name,
+                first_line: lines.len(),
+            });
+            lines.extend(file_lines);
+        }
+        Source::new(Lines::Owned(lines), true, file_starts)
+    }
+
+    /// A complete source made up of the output of a command.
+    pub fn from_command(
+        mut stdout: Vec<String>,
+        stderr: Vec<String>,
+        exit_status: ExitStatus,
+    ) -> Source {
+        let first_error_line = stdout.len();
+        let has_errors = !stderr.is_empty();
+        stdout.extend(stderr);
+        let source = Source::new(Lines::Owned(stdout), true, vec![]);
+        {
+            let mut data = source.inner.write().unwrap();
+            data.first_error_line = Some(first_error_line).filter(|_| has_errors);
+            data.exit_status = Some(exit_status);
+        }
+        source
+    }
+
+    /// A complete source backed by a memory-mapped UTF-8 file, starting at
+    /// byte `start`. Only an index of where lines start is kept in memory.
+    /// Lines end with NUL instead of a newline if `null_data` is set.
+    ///
+    /// The file must not be truncated while it is mapped: reading a line from
+    /// the lost part raises SIGBUS. Files that are followed while they change
+    /// are read into memory instead, see `FileFollower`.
+    pub fn mapped(map: Mmap, start: usize, null_data: bool) -> Source {
+        let terminator = if null_data { b'\0' } else { b'\n' };
+        let mut line_starts: Vec<usize> = vec![];
+        let mut lossy = false;
+        let mut line_start = start;
+        while line_start < map.len() {
+            line_starts.push(line_start);
+            let line_end = memchr::memchr(terminator, &map[line_start..])
+                .map_or(map.len(), |offset| line_start + offset);
+            lossy |= std::str::from_utf8(&map[line_start..line_end]).is_err();
+            line_start = line_end + 1;
+        }
+        line_starts.push(line_start);
+        let source = Source::new(
+            Lines::Mapped {
+                map,
+                line_starts,
+                null_data,
+            },
+            true,
+            vec![],
+        );
+        if lossy {
+            source.mark_lossy();
+        }
+        source
+    }
+
+    /// An empty source that is filled using `append` until `finish` is called.
+    pub fn streaming() -> Source {
+        Source::new(Lines::Owned(vec![]), false, vec![])
+    }
+
+    /// The lines of a followed file. Lines keep being appended to it, so it
+    /// is never complete.
+    pub fn following(lines: Vec<String>) -> Source {
+        Source::new(Lines::Owned(lines), false, vec![])
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Calls `f` with the line at `index`, without copying it.
+    pub fn with_line<T, F: FnOnce(&str) -> T>(&self, index: usize, f: F) -> Option<T> {
+        self.inner
+            .read()
+            .unwrap()
+            .lines
+            .get(index)
+            .map(|line| f(&line))
+    }
+
+    pub fn get(&self, index: usize) -> Option<String> {
+        self.with_line(index, |line| line.to_string())
+    }
+
+    /// The name of the file the line at `index` was read from, and its line
+    /// number within that file, if the source consists of multiple files.
+    pub fn file_position(&self, index: usize) -> Option<(String, usize)> {
+        let data = self.inner.read().unwrap();
+        let file_index = data.files.partition_point(|f| f.first_line <= index);
+        file_index.checked_sub(1).map(|i| {
+            let file = &data.files[i];
+            (file.name.clone(), index - file.first_line + 1)
+        })
+    }
+
+    pub fn has_file_names(&self) -> bool {
+        !self.inner.read().unwrap().files.is_empty()
+    }
+
+    /// Whether the line at `index` was written to stderr by a command.
+    pub fn is_error_line(&self, index: usize) -> bool {
+        self.inner
+            .read()
+            .unwrap()
+            .first_error_line
+            .is_some_and(|first| index >= first)
+    }
+
+    /// How the command producing the lines exited, if they came from a command.
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.inner.read().unwrap().exit_status
+    }
+
+    pub fn reload_error(&self) -> Option<String> {
+        self.inner.read().unwrap().reload_error.clone()
+    }
+
+    /// Notes that reading the input again failed, and these lines are kept.
+    pub fn set_reload_error(&self, error: String) {
+        self.inner.write().unwrap().reload_error = Some(error);
+    }
+
+    /// Whether all input has been read.
+    pub fn is_complete(&self) -> bool {
+        self.inner.read().unwrap().complete
+    }
+
+    /// Whether some of the lines were decoded with replacement characters.
+    pub fn is_lossy(&self) -> bool {
+        self.inner.read().unwrap().lossy
+    }
+
+    pub fn mark_lossy(&self) {
+        self.inner.write().unwrap().lossy = true;
+    }
+
+    /// Appends lines to the source. A mapped source is read into memory first,
+    /// as the lines can't be added to the map.
+    pub fn append(&self, new_lines: Vec<String>) {
+        let mut data = self.inner.write().unwrap();
+        if let Lines::Mapped { .. } = data.lines {
+            let lines: Vec<String> = (0..data.lines.len())
+                .filter_map(|i| data.lines.get(i).map(Cow::into_owned))
+                .collect();
+            data.lines = Lines::Owned(lines);
+        }
+        if let Lines::Owned(lines) = &mut data.lines {
+            lines.extend(new_lines);
+        }
+    }
+
+    pub fn finish(&self) {
+        self.inner.write().unwrap().complete = true;
+    }
+}
+
+impl fmt::Debug for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries((0..self.len()).filter_map(|i| self.get(i)))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+
+    fn mapped(name: &str, content: &[u8], start: usize, null_data: bool) -> Source {
+        let path = std::env::temp_dir().join(format!("igrepper-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        let map = unsafe { Mmap::map(&fs::File::open(&path).unwrap()) }.unwrap();
+        fs::remove_file(&path).unwrap();
+        Source::mapped(map, start, null_data)
+    }
+
+    #[test]
+    fn mapped_lines() {
+        let source = mapped("mapped", b"\xef\xbb\xbfone\r\n\ntwo\nthree", 3, false);
+        assert_eq!(
+            format!("{:?}", source),
+            "[\"one\", \"\", \"two\", \"three\"]"
+        );
+        assert_eq!(source.len(), 4);
+        assert_eq!(source.with_line(2, |line| line.len()), Some(3));
+        assert_eq!(source.get(4), None);
+        assert!(source.is_complete());
+        assert!(!source.is_lossy());
+
+        let source = mapped("mapped_terminated", b"one\n", 0, false);
+        assert_eq!(format!("{:?}", source), "[\"one\"]");
+    }
+
+    #[test]
+    fn append_to_mapped() {
+        let source = mapped("mapped_append", b"one\r\ntwo\n", 0, false);
+        source.append(vec![String::from("three")]);
+        assert_eq!(format!("{:?}", source), "[\"one\", \"two\", \"three\"]");
+    }
+
+    #[test]
+    fn mapped_invalid_utf8() {
+        let source = mapped("mapped_invalid", b"one\nt\xffo\n", 0, false);
+        assert_eq!(source.get(1), Some(String::from("t\u{fffd}o")));
+        assert!(source.is_lossy());
+    }
+
+    #[test]
+    fn mapped_null_data() {
+        let source = mapped("mapped_null", b"one\r\ntwo\0three\0", 0, true);
+        assert_eq!(format!("{:?}", source), "[\"one\\r\\ntwo\", \"three\"]");
+    }
+}
@@ -1,55 +1,88 @@
-use std::cmp;
-use std::sync::Arc;
+use crate::igrepper::source::Source;
 
 /// Abstraction over the input lines.
 /// `Raw` holds the original input lines directly.
-/// `Buffered` is a growable buffer that Core populates from a parent OutputGenerator
+/// `Buffered` is a growable buffer of indices into the original input lines,
+/// that Core populates from a parent OutputGenerator
 #[derive(Debug)]
 pub(crate) enum SourceLines {
-    Raw(Arc<Vec<String>>),
+    Raw(Source),
     Buffered {
-        buffer: Vec<String>,
+        source: Source,
+        buffer: Vec<usize>,
         parent_exhausted: bool,
     },
 }
 
 impl SourceLines {
-    pub fn new_buffered() -> Self {
+    pub fn new_buffered(source: Source) -> Self {
         SourceLines::Buffered {
+            source,
             buffer: Vec::new(),
             parent_exhausted: false,
         }
     }
 
-    pub(crate) fn get(&self, index: usize) -> Option<String> {
+    /// Calls `f` with the line at `index`, without copying it.
+    pub(crate) fn with_line<T, F: FnOnce(&str) -> T>(&self, index: usize, f: F) -> Option<T> {
         match self {
-            SourceLines::Raw(v) => v.get(index).cloned(),
-            SourceLines::Buffered { buffer, .. } => buffer.get(index).cloned(),
+            SourceLines::Raw(v) => v.with_line(index, f),
+            SourceLines::Buffered { source, buffer, .. } => {
+                buffer.get(index).and_then(|i| source.with_line(*i, f))
+            }
+        }
+    }
+
+    /// Returns the index in the original input of the line at `index`.
+    pub(crate) fn source_index(&self, index: usize) -> usize {
+        match self {
+            SourceLines::Raw(_) => index,
+            SourceLines::Buffered { buffer, .. } => buffer[index],
+        }
+    }
+
+    pub(crate) fn source(&self) -> &Source {
+        match self {
+            SourceLines::Raw(v) => v,
+            SourceLines::Buffered { source, .. } => source,
         }
     }
 
     pub(crate) fn is_exhausted(&self, lines_processed: u32) -> bool {
         match self {
-            SourceLines::Raw(v) => lines_processed >= v.len() as u32,
+            SourceLines::Raw(v) => v.is_complete() && lines_processed >= v.len() as u32,
             SourceLines::Buffered {
                 buffer,
                 parent_exhausted,
+                ..
             } => *parent_exhausted && lines_processed >= buffer.len() as u32,
         }
     }
 
-    pub(crate) fn available_count(&self, lines_processed: u32) -> u32 {
+    /// Whether no more lines will be added.
+    pub(crate) fn is_complete(&self) -> bool {
         match self {
-            SourceLines::Raw(v) => v.len() as u32,
-            SourceLines::Buffered { buffer, .. } => cmp::max(buffer.len() as u32, lines_processed),
+            SourceLines::Raw(v) => v.is_complete(),
+            SourceLines::Buffered {
+                parent_exhausted, ..
+            } => *parent_exhausted,
         }
     }
 
-    /// Appends lines to a Buffered source and marks whether the parent is done.
-    pub(crate) fn extend_buffer(&mut self, new_lines: &[String], parent_exhausted: bool) {
+    /// Whether the line at `index` can be processed, which requires the
+    /// `lookahead` lines after it to be available until the source is complete.
+    pub(crate) fn can_process(&self, index: u32, lookahead: u32) -> bool {
+        let available = self.buffered_len();
+        (index as usize) < available
+            && (self.is_complete() || index as usize + (lookahead as usize) < available)
+    }
+
+    /// Appends source indices to a Buffered source and marks whether the parent is done.
+    pub(crate) fn extend_buffer(&mut self, new_lines: &[usize], parent_exhausted: bool) {
         if let SourceLines::Buffered {
             buffer,
             parent_exhausted: exhausted,
+            ..
         } = self
         {
             buffer.extend_from_slice(new_lines);
@@ -1,52 +1,260 @@
-use super::regex::{Error, Regex};
+use super::regex;
 use crate::igrepper::constants::CASE_INSENSITIVE_PREFIX;
+use crate::igrepper::matcher::{ColorMode, Engine, Error, Matcher};
+use crate::igrepper::output_generator::DisplayMode;
+use crate::igrepper::source::Source;
 use crate::igrepper::trimming::{content_width, pager_content_height, pager_window_height};
 use std::cmp;
-use std::sync::Arc;
+use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Clone)]
 pub struct State {
-    source_lines: Arc<Vec<String>>,
+    source_lines: Source,
     search_lines: Vec<SearchLine>,
-    last_valid_regex: Regex,
+    last_valid_regex: Matcher,
     pager_x: u32,
     pager_y: u32,
     max_y: u32,
     max_x: u32,
+    /// The search lines of earlier branches of a union, see `branch`.
+    branches: Vec<Vec<SearchLine>>,
+    view: ViewOptions,
+}
+
+/// How the output is shown, apart from what is searched for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ViewOptions {
+    pub display_mode: DisplayMode,
+    /// Whether output lines are shown with their line number in the source.
+    pub line_numbers: bool,
+    /// How the matches of the current search line are coloured.
+    pub color_mode: ColorMode,
+}
+
+/// How a search line treats upper and lower case.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CaseMode {
+    Sensitive,
+    #[default]
+    Insensitive,
+    /// Insensitive, unless the search line contains an uppercase letter.
+    Smart,
+}
+
+impl CaseMode {
+    /// The mode following this one when cycling through them.
+    pub fn next(self) -> CaseMode {
+        match self {
+            CaseMode::Insensitive => CaseMode::Sensitive,
+            CaseMode::Sensitive => CaseMode::Smart,
+            CaseMode::Smart => CaseMode::Insensitive,
+        }
+    }
+}
+
+impl FromStr for CaseMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sensitive" => Ok(CaseMode::Sensitive),
+            "insensitive" => Ok(CaseMode::Insensitive),
+            "smart" => Ok(CaseMode::Smart),
+            _ => Err(format!(
+                "Unknown case mode '{}', expected one of sensitive, insensitive, smart",
+                s
+            )),
+        }
+    }
+}
+
+/// Number of lines shown around each match of a search line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Context {
+    pub before: u32,
+    pub after: u32,
+}
+
+impl Context {
+    pub fn new(before: u32, after: u32) -> Context {
+        Context { before, after }
+    }
+
+    /// The same number of lines before and after each match.
+    pub fn symmetric(lines: u32) -> Context {
+        Context::new(lines, lines)
+    }
+
+    /// Enough context for both `self` and `other`.
+    pub fn widest(self, other: Context) -> Context {
+        Context::new(
+            cmp::max(self.before, other.before),
+            cmp::max(self.after, other.after),
+        )
+    }
+
+    pub fn is_symmetric(&self) -> bool {
+        self.before == self.after
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.before == 0 && self.after == 0
+    }
+
+    fn adjust(lines: u32, diff: i32) -> u32 {
+        if diff > 0 {
+            lines.saturating_add(diff as u32)
+        } else {
+            lines.saturating_sub(-diff as u32)
+        }
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_symmetric() {
+            write!(f, "{}", self.before)
+        } else {
+            write!(f, "{} before, {} after", self.before, self.after)
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct SearchLine {
     pub line: String,
-    pub context: u32,
-    pub case_sensitive: bool,
+    pub context: Context,
+    pub case_mode: CaseMode,
     pub inverse: bool,
+    /// Whether `line` is matched verbatim instead of as a regex.
+    pub literal: bool,
+    pub engine: Engine,
+    /// Whether `line` is a query of space separated terms, see `Matcher::query`.
+    pub query: bool,
 }
 
 impl SearchLine {
     pub fn new(line: String, context: u32, case_sensitive: bool, inverse: bool) -> SearchLine {
         SearchLine {
             line,
-            context,
-            case_sensitive,
+            context: Context::symmetric(context),
+            case_mode: if case_sensitive {
+                CaseMode::Sensitive
+            } else {
+                CaseMode::Insensitive
+            },
             inverse,
+            literal: false,
+            engine: Engine::Standard,
+            query: false,
+        }
+    }
+
+    /// Whether the line is matched case sensitively, after resolving smartcase.
+    pub fn is_case_sensitive(&self) -> bool {
+        match self.case_mode {
+            CaseMode::Sensitive => true,
+            CaseMode::Insensitive => false,
+            CaseMode::Smart if self.is_regex() => regex_has_uppercase(&self.line),
+            CaseMode::Smart => self.line.chars().any(char::is_uppercase),
         }
     }
 
+    /// Whether `line` is a regex, rather than literal or fuzzy.
+    pub fn is_regex(&self) -> bool {
+        !self.literal && !matches!(self.engine, Engine::Fuzzy { .. })
+    }
+
     pub fn line_with_sensitivity_prefix(&self) -> String {
-        if self.case_sensitive {
+        if self.is_case_sensitive() {
             self.line.clone()
         } else {
             format!("{}{}", CASE_INSENSITIVE_PREFIX, self.line)
         }
     }
-    pub fn construct_regex(&self) -> Result<Regex, Error> {
-        Regex::new(self.line_with_sensitivity_prefix().as_str())
+    pub fn construct_regex(&self) -> Result<Matcher, Error> {
+        if self.query {
+            Matcher::query(&self.line, |term| {
+                SearchLine {
+                    line: String::from(term),
+                    query: false,
+                    ..self.clone()
+                }
+                .construct_regex()
+            })
+        } else if let Engine::Fuzzy { ranked } = self.engine {
+            Ok(Matcher::fuzzy(&self.line, self.is_case_sensitive(), ranked))
+        } else if self.literal {
+            let escaped = SearchLine {
+                line: regex::escape(&self.line),
+                literal: false,
+                ..self.clone()
+            };
+            escaped.construct_regex()
+        } else {
+            Matcher::new(self.line_with_sensitivity_prefix().as_str(), self.engine)
+        }
     }
 }
 
-fn default_regex() -> Regex {
-    Regex::new(CASE_INSENSITIVE_PREFIX).unwrap()
+fn default_regex() -> Matcher {
+    Matcher::new(CASE_INSENSITIVE_PREFIX, Engine::Standard).unwrap()
+}
+
+/// Whether `pattern` matches an uppercase letter. Escapes, like `\S`,
+/// `\p{Greek}` or `\x{1F}`, group names and flags are not letters to match.
+fn regex_has_uppercase(pattern: &str) -> bool {
+    let chars: Vec<char> = pattern.chars().collect();
+    // The position after the first `close` at or after `i`
+    let past = |i: usize, close: char| {
+        chars[i..]
+            .iter()
+            .position(|&c| c == close)
+            .map_or(chars.len(), |len| i + len + 1)
+    };
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                let escape = chars.get(i + 1).copied();
+                i += 2;
+                match (escape, chars.get(i)) {
+                    (Some('p' | 'P' | 'x' | 'u' | 'U'), Some('{')) => i = past(i, '}'),
+                    (Some('k'), Some('<')) => i = past(i, '>'),
+                    (Some('p' | 'P'), Some(_)) => i += 1,
+                    (Some(escape @ ('x' | 'u' | 'U')), _) => {
+                        let digits = match escape {
+                            'x' => 2,
+                            'u' => 4,
+                            _ => 8,
+                        };
+                        let end = cmp::min(i + digits, chars.len());
+                        while i < end && chars[i].is_ascii_hexdigit() {
+                            i += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            '(' if chars.get(i + 1) == Some(&'?') => {
+                i += 2;
+                if chars.get(i) == Some(&'P') && chars.get(i + 1) == Some(&'<') {
+                    i += 1;
+                }
+                if chars.get(i) == Some(&'<') && !matches!(chars.get(i + 1), Some('=' | '!')) {
+                    i = past(i, '>');
+                } else {
+                    while i < chars.len() && (chars[i].is_ascii_alphabetic() || chars[i] == '-') {
+                        i += 1;
+                    }
+                }
+            }
+            c if c.is_uppercase() => return true,
+            _ => i += 1,
+        }
+    }
+    false
 }
 
 impl State {
@@ -57,6 +265,25 @@ impl State {
         pager_y: u32,
         max_y: u32,
         max_x: u32,
+    ) -> State {
+        State::new_with_source(
+            Source::complete(source_lines),
+            search_lines,
+            pager_x,
+            pager_y,
+            max_y,
+            max_x,
+        )
+    }
+
+    /// Creates a State reading from an existing, possibly still growing, source.
+    pub fn new_with_source(
+        source_lines: Source,
+        search_lines: Vec<SearchLine>,
+        pager_x: u32,
+        pager_y: u32,
+        max_y: u32,
+        max_x: u32,
     ) -> State {
         assert_ne!(
             0,
@@ -72,40 +299,21 @@ impl State {
                     "All except the last line in 'search_lines' need to be valid regexes"
                 );
             });
-        let regex: Regex = search_lines
+        let regex: Matcher = search_lines
             .last()
             .unwrap()
             .construct_regex()
             .unwrap_or(default_regex());
-        let source_lines = Arc::new(source_lines);
-        State::new_with_regex(
-            source_lines,
-            search_lines,
-            regex,
-            pager_x,
-            pager_y,
-            max_y,
-            max_x,
-        )
-    }
-
-    fn new_with_regex(
-        source_lines: Arc<Vec<String>>,
-        search_lines: Vec<SearchLine>,
-        last_valid_regex: Regex,
-        pager_x: u32,
-        pager_y: u32,
-        max_y: u32,
-        max_x: u32,
-    ) -> State {
         State {
             source_lines,
             search_lines,
-            last_valid_regex,
+            last_valid_regex: regex,
             pager_x,
             pager_y,
             max_y,
             max_x,
+            branches: vec![],
+            view: ViewOptions::default(),
         }
     }
     pub fn max_y(&self) -> u32 {
@@ -120,7 +328,7 @@ impl State {
     pub fn pager_x(&self) -> u32 {
         self.pager_x
     }
-    pub fn current_context(&self) -> u32 {
+    pub fn current_context(&self) -> Context {
         self.search_lines.last().unwrap().context
     }
     pub fn inverted(&self) -> bool {
@@ -129,6 +337,43 @@ impl State {
     pub fn search_lines(&self) -> Vec<SearchLine> {
         self.search_lines.clone()
     }
+    pub fn view(&self) -> ViewOptions {
+        self.view
+    }
+    pub fn display_mode(&self) -> DisplayMode {
+        self.view.display_mode
+    }
+    pub fn line_numbers(&self) -> bool {
+        self.view.line_numbers
+    }
+    pub fn color_mode(&self) -> ColorMode {
+        self.view.color_mode
+    }
+    pub fn branches(&self) -> Vec<Vec<SearchLine>> {
+        self.branches.clone()
+    }
+    /// The earlier branches and the current one, each as a State of its own.
+    pub fn branch_states(&self) -> Vec<State> {
+        let mut states: Vec<State> = self
+            .branches
+            .iter()
+            .map(|search_lines| {
+                State::new_with_source(
+                    self.source(),
+                    search_lines.clone(),
+                    self.pager_x,
+                    self.pager_y,
+                    self.max_y,
+                    self.max_x,
+                )
+            })
+            .collect();
+        states.push(State {
+            branches: vec![],
+            ..self.clone()
+        });
+        states
+    }
     pub fn search_line_strings(&self) -> Vec<String> {
         self.search_lines
             .iter()
@@ -136,22 +381,23 @@ impl State {
             .map(|s| s.line)
             .collect::<Vec<String>>()
     }
-    pub fn source_lines(&self) -> &[String] {
-        &self.source_lines
+    pub fn source(&self) -> Source {
+        self.source_lines.clone()
     }
-    pub fn source_lines_arc(&self) -> Arc<Vec<String>> {
-        Arc::clone(&self.source_lines)
+    /// Whether all input has been read.
+    pub fn source_complete(&self) -> bool {
+        self.source_lines.is_complete()
     }
 
     pub fn regex_valid(&self) -> bool {
         self.regex().is_ok()
     }
 
-    pub fn regex(&self) -> Result<Regex, Error> {
+    pub fn regex(&self) -> Result<Matcher, Error> {
         self.search_lines.last().unwrap().construct_regex()
     }
 
-    pub fn last_valid_regex(&self) -> Regex {
+    pub fn last_valid_regex(&self) -> Matcher {
         self.last_valid_regex.clone()
     }
 
@@ -166,15 +412,11 @@ impl State {
         let regex = last_search_line
             .construct_regex()
             .unwrap_or(self.last_valid_regex);
-        State::new_with_regex(
-            self.source_lines,
+        State {
             search_lines,
-            regex,
-            self.pager_x,
-            self.pager_y,
-            self.max_y,
-            self.max_x,
-        )
+            last_valid_regex: regex,
+            ..self
+        }
     }
     pub fn push_search_char(self, new_char: char) -> State {
         let mut search_lines = self.search_lines.clone();
@@ -183,15 +425,11 @@ impl State {
         let regex = last_search_line
             .construct_regex()
             .unwrap_or(self.last_valid_regex);
-        State::new_with_regex(
-            self.source_lines,
+        State {
             search_lines,
-            regex,
-            self.pager_x,
-            self.pager_y,
-            self.max_y,
-            self.max_x,
-        )
+            last_valid_regex: regex,
+            ..self
+        }
     }
 
     pub fn accept_partial_match(self) -> State {
@@ -200,74 +438,99 @@ impl State {
             search_lines.push(SearchLine {
                 line: String::from(""),
                 context: search_lines.last().unwrap().context,
-                case_sensitive: search_lines.last().unwrap().case_sensitive,
+                case_mode: search_lines.last().unwrap().case_mode,
                 inverse: search_lines.last().unwrap().inverse,
+                literal: search_lines.last().unwrap().literal,
+                engine: search_lines.last().unwrap().engine,
+                query: search_lines.last().unwrap().query,
             });
-            return State::new_with_regex(
-                self.source_lines,
+            return State {
                 search_lines,
-                default_regex(),
-                self.pager_x,
-                self.pager_y,
-                self.max_y,
-                self.max_x,
-            );
+                last_valid_regex: default_regex(),
+                ..self
+            };
         }
         self
     }
+    /// Starts another branch of a union, from the parent of the current search
+    /// line. The output has the lines of all branches, in their original order.
+    pub fn branch(self) -> State {
+        if !self.search_lines.last().unwrap().line.is_empty() && self.regex_valid() {
+            let mut branches = self.branches.clone();
+            branches.push(self.search_lines.clone());
+            let mut search_lines = self.search_lines.clone();
+            let last_search_line = search_lines.pop().unwrap();
+            search_lines.push(SearchLine {
+                line: String::from(""),
+                ..last_search_line
+            });
+            return State {
+                search_lines,
+                last_valid_regex: default_regex(),
+                branches,
+                ..self
+            };
+        }
+        self
+    }
+    /// Reverts the current search line. Reverting the only search line of a
+    /// branch goes back to the previous branch.
     pub fn revert_partial_match(self) -> State {
+        if self.search_lines.len() == 1 && !self.branches.is_empty() {
+            let mut branches = self.branches.clone();
+            let search_lines = branches.pop().unwrap();
+            let regex: Matcher = search_lines.last().unwrap().construct_regex().unwrap(); // branches end with valid regexes
+            return State {
+                search_lines,
+                last_valid_regex: regex,
+                branches,
+                ..self
+            };
+        }
         if self.search_lines.len() > 1 {
             let mut search_lines = self.search_lines.clone();
             search_lines.pop();
-            let regex: Regex = search_lines.last().unwrap().construct_regex().unwrap(); // previous lines should be valid regexes
-            return State::new_with_regex(
-                self.source_lines,
+            let regex: Matcher = search_lines.last().unwrap().construct_regex().unwrap(); // previous lines should be valid regexes
+            return State {
                 search_lines,
-                regex,
-                self.pager_x,
-                self.pager_y,
-                self.max_y,
-                self.max_x,
-            );
+                last_valid_regex: regex,
+                ..self
+            };
         }
         self
     }
     pub fn set_max_yx(self, max_y: u32, max_x: u32) -> State {
-        State::new_with_regex(
-            self.source_lines,
-            self.search_lines,
-            self.last_valid_regex,
-            self.pager_x,
-            self.pager_y,
+        State {
             max_y,
             max_x,
-        )
+            ..self
+        }
     }
     pub fn modify_context(self, context_diff: i32) -> State {
+        self.modify_context_sides(context_diff, context_diff)
+    }
+    pub fn modify_context_before(self, context_diff: i32) -> State {
+        self.modify_context_sides(context_diff, 0)
+    }
+    pub fn modify_context_after(self, context_diff: i32) -> State {
+        self.modify_context_sides(0, context_diff)
+    }
+    fn modify_context_sides(self, before_diff: i32, after_diff: i32) -> State {
         let mut lines = self.search_lines.clone();
         let last_line = lines.pop().unwrap();
-        let mut context = last_line.context;
-        if context_diff > 0 {
-            context = context.saturating_add(context_diff as u32);
-        } else {
-            context = context.saturating_sub(-context_diff as u32);
-        }
-        lines.push(SearchLine::new(
-            last_line.line,
+        let context = Context::new(
+            Context::adjust(last_line.context.before, before_diff),
+            Context::adjust(last_line.context.after, after_diff),
+        );
+        lines.push(SearchLine {
             context,
-            last_line.case_sensitive,
-            last_line.inverse,
-        ));
-
-        State::new_with_regex(
-            self.source_lines,
-            lines,
-            self.last_valid_regex,
-            self.pager_x,
-            self.pager_y,
-            self.max_y,
-            self.max_x,
-        )
+            ..last_line
+        });
+
+        State {
+            search_lines: lines,
+            ..self
+        }
     }
     /// Moves the pager horizontally
     /// Clamps the new pager position to only allow valid values
@@ -280,55 +543,38 @@ impl State {
         } else {
             self.pager_x.saturating_sub(amount.wrapping_abs() as u32)
         };
-        State::new_with_regex(
-            self.source_lines,
-            self.search_lines,
-            self.last_valid_regex,
-            pager_x,
-            self.pager_y,
-            self.max_y,
-            self.max_x,
-        )
+        State { pager_x, ..self }
     }
 
     /// Moves the pager vertically
     /// Clamps the new pager position to only allow valid values
     pub fn page_y(self, amount: i32, output_line_count: u32) -> State {
         let pager_y: u32 = if amount >= 0 {
-            let pager_y_max = output_line_count.saturating_sub(pager_content_height(
-                pager_window_height(self.max_y, self.search_lines.len() as u32),
-            ));
+            let pager_y_max =
+                output_line_count.saturating_sub(pager_content_height(pager_window_height(
+                    self.max_y,
+                    (self.search_lines.len() + self.branches.len()) as u32,
+                )));
             cmp::min(pager_y_max, self.pager_y.saturating_add(amount as u32))
         } else {
             self.pager_y.saturating_sub(amount.wrapping_abs() as u32)
         };
-        State::new_with_regex(
-            self.source_lines,
-            self.search_lines.clone(),
-            self.last_valid_regex,
-            self.pager_x,
-            pager_y,
-            self.max_y,
-            self.max_x,
-        )
+        State { pager_y, ..self }
     }
 
-    pub fn toggle_case_sensitivity(self) -> State {
+    /// Switches between case insensitive, sensitive and smartcase.
+    pub fn cycle_case_mode(self) -> State {
         let mut search_lines = self.search_lines.clone();
         let last_search_line = search_lines.last_mut().unwrap();
-        last_search_line.case_sensitive = !last_search_line.case_sensitive;
+        last_search_line.case_mode = last_search_line.case_mode.next();
         let regex = last_search_line
             .construct_regex()
             .unwrap_or(self.last_valid_regex);
-        State::new_with_regex(
-            self.source_lines,
+        State {
             search_lines,
-            regex,
-            self.pager_x,
-            self.pager_y,
-            self.max_y,
-            self.max_x,
-        )
+            last_valid_regex: regex,
+            ..self
+        }
     }
 
     pub fn toggle_inverted(self) -> State {
@@ -338,31 +584,142 @@ impl State {
         let regex = last_search_line
             .construct_regex()
             .unwrap_or(self.last_valid_regex);
-        State::new_with_regex(
-            self.source_lines,
+        State {
             search_lines,
-            regex,
-            self.pager_x,
-            self.pager_y,
-            self.max_y,
-            self.max_x,
-        )
+            last_valid_regex: regex,
+            ..self
+        }
+    }
+
+    /// Switches between the standard, fancy and fuzzy engines.
+    pub fn cycle_engine(self) -> State {
+        let mut search_lines = self.search_lines.clone();
+        let last_search_line = search_lines.last_mut().unwrap();
+        last_search_line.engine = last_search_line.engine.next();
+        let regex = last_search_line
+            .construct_regex()
+            .unwrap_or(self.last_valid_regex);
+        State {
+            search_lines,
+            last_valid_regex: regex,
+            ..self
+        }
+    }
+
+    /// Switches a fuzzy search between ranked and source order.
+    pub fn toggle_ranked(self) -> State {
+        let mut search_lines = self.search_lines.clone();
+        let last_search_line = search_lines.last_mut().unwrap();
+        last_search_line.engine = last_search_line.engine.toggle_ranked();
+        let regex = last_search_line
+            .construct_regex()
+            .unwrap_or(self.last_valid_regex);
+        State {
+            search_lines,
+            last_valid_regex: regex,
+            ..self
+        }
+    }
+
+    pub fn toggle_query(self) -> State {
+        let mut search_lines = self.search_lines.clone();
+        let last_search_line = search_lines.last_mut().unwrap();
+        last_search_line.query = !last_search_line.query;
+        let regex = last_search_line
+            .construct_regex()
+            .unwrap_or(self.last_valid_regex);
+        State {
+            search_lines,
+            last_valid_regex: regex,
+            ..self
+        }
+    }
+
+    /// Switches between showing whole lines, only the matches, and unique
+    /// matches or lines with their counts.
+    pub fn cycle_display_mode(self) -> State {
+        // The number of output lines changes
+        State {
+            view: ViewOptions {
+                display_mode: self.view.display_mode.next(),
+                ..self.view
+            },
+            pager_y: 0,
+            ..self
+        }
+    }
+    pub fn toggle_unique_order(self) -> State {
+        State {
+            view: ViewOptions {
+                display_mode: self.view.display_mode.toggle_order(),
+                ..self.view
+            },
+            ..self
+        }
+    }
+    pub fn toggle_line_numbers(self) -> State {
+        State {
+            view: ViewOptions {
+                line_numbers: !self.view.line_numbers,
+                ..self.view
+            },
+            ..self
+        }
+    }
+    /// Switches between colouring matches by their text, by capture group and
+    /// by branch.
+    pub fn cycle_color_mode(self) -> State {
+        State {
+            view: ViewOptions {
+                color_mode: self.view.color_mode.next(),
+                ..self.view
+            },
+            ..self
+        }
+    }
+    pub fn toggle_literal(self) -> State {
+        let mut search_lines = self.search_lines.clone();
+        let last_search_line = search_lines.last_mut().unwrap();
+        last_search_line.literal = !last_search_line.literal;
+        let regex = last_search_line
+            .construct_regex()
+            .unwrap_or(self.last_valid_regex);
+        State {
+            search_lines,
+            last_valid_regex: regex,
+            ..self
+        }
     }
 
     pub fn empty_search_lines(&self) -> bool {
         self.search_lines.len() == 1 && self.search_lines[0].line.is_empty()
     }
 
-    pub fn set_source_lines(self, source_lines: Vec<String>) -> State {
-        State::new_with_regex(
-            Arc::new(source_lines),
-            self.search_lines,
-            self.last_valid_regex,
-            self.pager_x,
-            self.pager_y,
-            self.max_y,
-            self.max_x,
-        )
+    /// Replaces the lines of a followed file, which has been truncated or reloaded.
+    pub fn set_followed_lines(self, source_lines: Vec<String>) -> State {
+        State {
+            source_lines: Source::following(source_lines),
+            ..self
+        }
+    }
+
+    pub fn set_source(self, source: Source) -> State {
+        State {
+            source_lines: source,
+            ..self
+        }
+    }
+
+    /// Adds lines to the end of the source, existing output stays valid.
+    pub fn append_source_lines(self, new_lines: Vec<String>) -> State {
+        self.source_lines.append(new_lines);
+        self
+    }
+
+    /// Marks the source as complete, no more lines will be appended.
+    pub fn finish_source(self) -> State {
+        self.source_lines.finish();
+        self
     }
 }
 
@@ -421,7 +778,7 @@ mod tests {
     fn push_char() {
         let source_lines = get_source_lines();
         let state = get_state(source_lines).push_search_char('e');
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"de\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)de\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"de\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)de\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
@@ -439,16 +796,16 @@ mod tests {
         let source_lines = get_source_lines();
         let state = get_state(source_lines).pop_search_char();
 
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
         let state = state.pop_search_char();
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
     fn accepting_match() {
         let source_lines = get_source_lines();
         let state = get_state(source_lines).accept_partial_match();
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
@@ -457,41 +814,54 @@ mod tests {
         let state = get_state(source_lines)
             .push_search_char('\\')
             .accept_partial_match();
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\\\\\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\\\\\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
     fn reverting_match() {
         let source_lines = get_source_lines();
         let state = get_state(source_lines).revert_partial_match();
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)abc\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)abc\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
     fn incrementing_context() {
         let source_lines = get_source_lines();
         let state = get_state(source_lines).modify_context(1);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 1, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 1, after: 1 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
         let state = state.modify_context(2);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 3, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 3, after: 3 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
+    }
+
+    #[test]
+    fn modifying_context_sides() {
+        let source_lines = get_source_lines();
+        let state = get_state(source_lines)
+            .modify_context(1)
+            .modify_context_before(2)
+            .modify_context_after(-1);
+        assert_eq!(state.current_context(), Context::new(3, 0));
+        assert_eq!(state.current_context().to_string(), "3 before, 0 after");
+        let state = state.modify_context_after(3);
+        assert_eq!(state.current_context().to_string(), "3");
     }
 
     #[test]
     fn decrementing_context() {
         let source_lines = get_source_lines();
         let state = get_state(source_lines).modify_context(-1);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
     fn page_y() {
         let source_lines = get_source_lines();
         let state = get_state(source_lines).page_y(1, 10);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 1, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 1, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
         let state = state.page_y(100, 10);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 7, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 7, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
         let state = state.page_y(-100, 10);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
@@ -499,11 +869,11 @@ mod tests {
         let longest_line_length = 15;
         let source_lines = get_source_lines();
         let state = get_state(source_lines).page_x(1, longest_line_length);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 1, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 1, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
         let state = state.page_x(100, longest_line_length);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 7, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 7, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
         let state = state.page_x(-100, longest_line_length);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 0, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
@@ -511,9 +881,9 @@ mod tests {
         let longest_line_length = 15;
         let source_lines = get_source_lines();
         let state = get_state(source_lines).page_x(1, longest_line_length);
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 1, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 1, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
         let state = state.toggle_inverted();
-        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: 0, case_sensitive: false, inverse: false }, SearchLine { line: \"d\", context: 0, case_sensitive: false, inverse: true }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 1, pager_y: 0, max_y: 10, max_x: 10 }");
+        assert_eq!(format!("{:?}", state), "State { source_lines: [\"one\", \"two\", \"three\"], search_lines: [SearchLine { line: \"abc\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: false, literal: false, engine: Standard, query: false }, SearchLine { line: \"d\", context: Context { before: 0, after: 0 }, case_mode: Insensitive, inverse: true, literal: false, engine: Standard, query: false }], last_valid_regex: Regex(\"(?i)d\"), pager_x: 1, pager_y: 0, max_y: 10, max_x: 10, branches: [], view: ViewOptions { display_mode: Lines, line_numbers: false, color_mode: Text } }");
     }
 
     #[test]
@@ -531,4 +901,95 @@ mod tests {
         let state = state.pop_search_char();
         assert!(state.empty_search_lines());
     }
+    #[test]
+    fn cycle_case_mode() {
+        let state = State::new(
+            get_source_lines(),
+            vec![SearchLine::new(String::from("a\\S"), 0, false, false)],
+            0,
+            0,
+            10,
+            10,
+        );
+        let state = state.cycle_case_mode();
+        assert_eq!(state.last_valid_regex().as_str(), "a\\S");
+        let state = state.cycle_case_mode();
+        assert_eq!(state.search_lines()[0].case_mode, CaseMode::Smart);
+        assert_eq!(state.last_valid_regex().as_str(), "(?i)a\\S");
+        let state = state.push_search_char('B');
+        assert_eq!(state.last_valid_regex().as_str(), "a\\SB");
+        let state = state.accept_partial_match();
+        assert_eq!(state.search_lines()[1].case_mode, CaseMode::Smart);
+        let state = state.revert_partial_match().cycle_case_mode();
+        assert_eq!(state.search_lines()[0].case_mode, CaseMode::Insensitive);
+    }
+
+    #[test]
+    fn smart_case_skips_escapes() {
+        let is_case_sensitive = |line: &str| {
+            SearchLine {
+                case_mode: CaseMode::Smart,
+                ..SearchLine::new(String::from(line), 0, false, false)
+            }
+            .is_case_sensitive()
+        };
+        for line in [
+            "\\p{Greek}\\S\\W\\B",
+            "\\pL\\PN",
+            "\\x{1F600}\\xFF\\u00E9\\U0001F600",
+            "(?P<Name>a)\\k<Name>",
+            "(?<Name>a)(?U:b)",
+        ] {
+            assert!(!is_case_sensitive(line), "{}", line);
+        }
+        for line in ["\\p{Greek}A", "\\xFFA", "(?<=A)b", "(?i:a)B", "\\SA"] {
+            assert!(is_case_sensitive(line), "{}", line);
+        }
+        let literal = SearchLine {
+            literal: true,
+            case_mode: CaseMode::Smart,
+            ..SearchLine::new(String::from("\\S"), 0, false, false)
+        };
+        assert!(literal.is_case_sensitive());
+    }
+
+    #[test]
+    fn toggle_literal() {
+        let state = State::new(
+            get_source_lines(),
+            vec![SearchLine::new(String::from("a.("), 0, false, false)],
+            0,
+            0,
+            10,
+            10,
+        );
+        assert!(!state.regex_valid());
+        let state = state.toggle_literal();
+        assert!(state.regex_valid());
+        assert_eq!(state.last_valid_regex().as_str(), "(?i)a\\.\\(");
+        let state = state.push_search_char('x').accept_partial_match();
+        assert!(state.search_lines()[1].literal);
+    }
+
+    #[test]
+    fn branch() {
+        let state = get_state(get_source_lines()).branch();
+        assert_eq!(state.search_line_strings(), vec!["abc", ""]);
+        assert_eq!(state.branches().len(), 1);
+        assert_eq!(state.branches()[0][1].line, "d");
+        // Only non-empty, valid search lines can be branched
+        assert_eq!(state.clone().branch().branches().len(), 1);
+
+        let state = state.revert_partial_match().push_search_char('x');
+        assert_eq!(state.search_line_strings(), vec!["abcx"]);
+        let branch_states = state.branch_states();
+        assert_eq!(branch_states.len(), 2);
+        assert_eq!(branch_states[0].search_line_strings(), vec!["abc", "d"]);
+        assert!(branch_states[1].branches().is_empty());
+
+        let state = state.revert_partial_match();
+        assert_eq!(state.search_line_strings(), vec!["abc", "d"]);
+        assert!(state.branches().is_empty());
+        assert_eq!(state.last_valid_regex().as_str(), "(?i)d");
+    }
 }
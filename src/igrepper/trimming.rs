@@ -1,11 +1,17 @@
 use crate::igrepper::constants::*;
+use crate::igrepper::matcher::{ColorMode, Coloring, Matcher};
 use crate::igrepper::output_generator::{Len, OutputGenerator};
-use crate::igrepper::state::SearchLine;
+use crate::igrepper::source::Source;
+use crate::igrepper::state::{SearchLine, State, ViewOptions};
 use crate::igrepper::types::{
-    Line, LineWithMatches, RenderState, StringWithColorIndex, StringWithColorIndexOrBreakLine,
+    Line, LineWithMatches, MatchPosition, RenderState, StringWithColorIndex,
+    StringWithColorIndexOrBreakLine,
 };
 use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::os::unix::process::ExitStatusExt;
 
 /// Returns a state that can be rendered to the screen
 ///
@@ -25,24 +31,23 @@ use std::collections::HashMap;
 ///       v ▓▓▓▓▓▓▓▓▓▓▓▓▓▓▓▓▓▓▓▓▓                        <- status line
 /// ```
 pub fn produce_render_state(
-    regex_valid: bool,
-    max_y: u32,
-    max_x: u32,
-    pager_y: u32,
-    pager_x: u32,
-    search_lines: &[SearchLine],
-    context: u32,
+    state: &State,
+    earlier_regexes: &[Matcher],
+    match_colors: &mut MatchColors,
     result_generator: &mut OutputGenerator,
 ) -> RenderState {
-    let input_window_height = input_window_height(max_y, search_lines.len() as u32);
-    let pager_content_height =
-        pager_content_height(pager_window_height(max_y, search_lines.len() as u32));
+    let (max_y, max_x) = (state.max_y(), state.max_x());
+    let search_lines = &state.search_lines();
+    let branches = &state.branches();
+    let input_lines = (search_lines.len() + branches.len()) as u32;
+    let input_window_height = input_window_height(max_y, input_lines);
+    let pager_content_height = pager_content_height(pager_window_height(max_y, input_lines));
 
     let output_display_lines = output_lines_display_format(
-        pager_y,
-        pager_x,
-        content_width(max_x),
+        state,
         pager_content_height,
+        earlier_regexes,
+        match_colors,
         result_generator,
     );
 
@@ -51,16 +56,27 @@ pub fn produce_render_state(
         Len::AtLeast(n) => format!(">{}", n),
     };
     let status_line = format!(
-        "matchedLines{} pageY: {}, pageX: {}, context: {}",
-        matched_lines, pager_y, pager_x, context
+        "matchedLines{} pageY: {}, pageX: {}, context: {}{}{}",
+        matched_lines,
+        state.pager_y(),
+        state.pager_x(),
+        state.current_context(),
+        color_mode_status(state.color_mode()),
+        source_status(&state.source())
     );
 
     RenderState {
-        regex_valid,
+        regex_valid: state.regex_valid(),
         max_y,
         max_x,
         input_window_height,
-        pager_window_height: pager_window_height(max_y, search_lines.len() as u32),
+        pager_window_height: pager_window_height(max_y, input_lines),
+        output_branch_lines: branch_lines_display_format(
+            input_window_height,
+            search_lines.len(),
+            branches,
+            content_width(max_x),
+        ),
         output_search_lines: search_lines_display_format(
             input_window_height,
             search_lines,
@@ -71,6 +87,37 @@ pub fn produce_render_state(
     }
 }
 
+/// Notes about how matches are coloured, appended to the status line.
+fn color_mode_status(color_mode: ColorMode) -> &'static str {
+    match color_mode {
+        ColorMode::Text => "",
+        ColorMode::Group => ", colors by group",
+        ColorMode::Branch => ", colors by branch",
+    }
+}
+
+/// Notes about the input, appended to the status line.
+fn source_status(source: &Source) -> String {
+    let mut status = String::new();
+    if let Some(exit_status) = source.exit_status().filter(|s| !s.success()) {
+        match (exit_status.code(), exit_status.signal()) {
+            (Some(code), _) => status.push_str(&format!(", exit code {}", code)),
+            (_, Some(signal)) => status.push_str(&format!(", killed by signal {}", signal)),
+            _ => {}
+        }
+    }
+    if source.is_lossy() {
+        status.push_str(", invalid input replaced");
+    }
+    if let Some(error) = source.reload_error() {
+        status.push_str(&format!(", reload failed: {}", error));
+    }
+    if !source.is_complete() {
+        status.push_str(", reading...");
+    }
+    status
+}
+
 /// Get the width of the application content, based
 /// on the max_x of the terminal.
 pub fn content_width(max_x: u32) -> u32 {
@@ -78,15 +125,23 @@ pub fn content_width(max_x: u32) -> u32 {
     max_x.saturating_sub(borders_width)
 }
 
-/// Using the screen dimensions and pager position, returns output
-/// that is visible.
+/// Using the screen dimensions and pager position of `state`, returns output
+/// that is visible. Matches are coloured as its view options ask for, and
+/// matches of the `earlier_regexes` are shown too.
 fn output_lines_display_format(
-    pager_y: u32,
-    pager_x: u32,
-    content_width: u32,
+    state: &State,
     pager_content_height: u32,
+    earlier_regexes: &[Matcher],
+    match_colors: &mut MatchColors,
     result_generator: &mut OutputGenerator,
 ) -> Vec<StringWithColorIndexOrBreakLine> {
+    let (pager_y, pager_x) = (state.pager_y(), state.pager_x());
+    let content_width = content_width(state.max_x());
+    let ViewOptions {
+        line_numbers,
+        color_mode,
+        ..
+    } = state.view();
     const REQUEST_BUFFER_SIZE: u32 = 10; // Request a little more than actually needed.
     result_generator.request(pager_y + pager_content_height + REQUEST_BUFFER_SIZE);
     let line_count_at_least = match result_generator.len() {
@@ -99,33 +154,279 @@ fn output_lines_display_format(
         line_count_at_least as usize,
     ) as u32;
     let visible_lines = result_generator.slice(first_line_no, last_line_no);
+    let coloring = result_generator.regex().coloring(color_mode);
 
-    // We want to colorize each unique match with the same color, so we create a closure
-    // that keeps track of the matches seen so far.
-    let mut matches_to_colors = HashMap::new();
-    let mut color_number: u32 = 0;
-    let mut get_color = |string: &str| -> u32 {
-        let s = String::from(string);
-        if let std::collections::hash_map::Entry::Vacant(e) = matches_to_colors.entry(s) {
-            e.insert(color_number);
-            if color_number < MAX_MATCH_COLORS as u32 - 1 {
-                color_number += 1;
-            }
+    // We want to colorize each unique match with the same color, also when scrolling,
+    // so the colours of the matches seen so far are kept in `match_colors`. The matches
+    // of each earlier search line are coloured from a family of colours of their own.
+    let mut get_color = |match_position: &MatchPosition, string: &str| -> u32 {
+        let stage = match_position.stage;
+        let colors = if stage == 0 {
+            MAX_MATCH_COLORS
+        } else {
+            STAGE_MATCH_COLOR_SHADES
+        } as u32;
+        let color = match match_position.color_group {
+            Some(group) => group % colors,
+            None => match_colors.get(stage, string, colors),
+        };
+        if stage == 0 {
+            color
+        } else {
+            let family = (stage as usize - 1) % STAGE_MATCH_COLORS.len();
+            (MAX_MATCH_COLORS + family * STAGE_MATCH_COLOR_SHADES) as u32 + color
         }
-        matches_to_colors[string]
     };
 
+    // With multiple input files, show the file and line number of each line in a gutter.
+    // Matches shown without their line always show the line number, unique
+    // rows show their count instead. Line numbers can be shown for all lines.
+    let file_names: Vec<Option<String>> = visible_lines
+        .iter()
+        .map(|line| match line {
+            Line::LineWithMatches(l) | Line::Match(l) if line_numbers => {
+                Some(result_generator.position_label(l))
+            }
+            Line::LineWithMatches(l) | Line::Match(l) if result_generator.has_file_names() => {
+                result_generator
+                    .file_position(l.source_index)
+                    .map(|(name, line_number)| format!("{}:{}", name, line_number))
+            }
+            Line::Match(l) => Some(format!("{}:", l.source_index + 1)),
+            Line::Unique { count, .. } => Some(count.to_string()),
+            _ => None,
+        })
+        .collect();
+    let gutter_width = gutter_width(&file_names, content_width);
+    let content_width = content_width.saturating_sub(gutter_width);
+
     visible_lines
         .iter()
         .map(|line| match line {
-            Line::BreakLine => StringWithColorIndexOrBreakLine::BreakLine,
-            Line::LineWithMatches(l) => {
-                trim_and_colorize_line(l, pager_x, content_width, &mut get_color)
+            Line::Unique { line, .. } => line.as_ref(),
+            line => line,
+        })
+        .zip(file_names)
+        .map(|(line, file_name)| match line {
+            // Unique rows have been unwrapped above
+            Line::BreakLine | Line::Unique { .. } => StringWithColorIndexOrBreakLine::BreakLine,
+            Line::LineWithMatches(l) | Line::Match(l) => {
+                let full_line = result_generator.line(l.source_index).unwrap_or_default();
+                let l = &match &coloring {
+                    Some(coloring) => LineWithMatches {
+                        source_index: l.source_index,
+                        matches: colored_matches(&full_line, &l.matches, coloring),
+                    },
+                    None => l.clone(),
+                };
+                let (original_line, l) = match line {
+                    Line::Match(match_line) => {
+                        let offset = match_line.matches.first().map_or(0, |m| m.start);
+                        (
+                            result_generator
+                                .matched_text(match_line)
+                                .unwrap_or_default(),
+                            &LineWithMatches {
+                                source_index: l.source_index,
+                                matches: l
+                                    .matches
+                                    .iter()
+                                    .map(|m| MatchPosition {
+                                        start: m.start - offset,
+                                        end: m.end - offset,
+                                        color_group: m.color_group,
+                                        stage: m.stage,
+                                    })
+                                    .collect(),
+                            },
+                        )
+                    }
+                    _ => (full_line, l),
+                };
+                let l = &match line {
+                    // Context lines have no matches, and get no earlier ones either
+                    Line::LineWithMatches(_)
+                        if !earlier_regexes.is_empty() && !l.matches.is_empty() =>
+                    {
+                        LineWithMatches {
+                            source_index: l.source_index,
+                            matches: with_earlier_matches(
+                                &original_line,
+                                &l.matches,
+                                earlier_regexes,
+                            ),
+                        }
+                    }
+                    _ => l.clone(),
+                };
+                let mut line = trim_and_colorize_line(
+                    &original_line,
+                    l,
+                    pager_x,
+                    content_width,
+                    &mut get_color,
+                );
+                if result_generator.is_error_line(l.source_index) {
+                    line = as_error_line(line);
+                }
+                match (file_name, line) {
+                    (
+                        Some(file_name),
+                        StringWithColorIndexOrBreakLine::StringWithColorIndex(mut parts),
+                    ) if gutter_width > 0 => {
+                        parts.insert(
+                            0,
+                            StringWithColorIndex::Gutter(gutter(&file_name, gutter_width)),
+                        );
+                        StringWithColorIndexOrBreakLine::StringWithColorIndex(parts)
+                    }
+                    (_, line) => line,
+                }
             }
         })
         .collect::<Vec<StringWithColorIndexOrBreakLine>>()
 }
 
+/// The colours given to matched texts, kept for as long as the output of a
+/// stage, so that a match keeps its colour when scrolling.
+#[derive(Debug, Default)]
+pub struct MatchColors {
+    /// Keyed by the stage of the match, see `MatchPosition::stage`, and its text
+    colors: HashMap<(u32, String), u32>,
+    /// How many colours have been handed out for each stage
+    handed_out: HashMap<u32, u32>,
+}
+
+impl MatchColors {
+    /// The colour of `text`, out of `colors` colours. Texts get the colours in
+    /// the order they are first seen. Once all colours are taken, a text gets
+    /// the colour of its hash, so that later texts still differ from each other.
+    fn get(&mut self, stage: u32, text: &str, colors: u32) -> u32 {
+        let handed_out = self.handed_out.entry(stage).or_insert(0);
+        *self
+            .colors
+            .entry((stage, String::from(text)))
+            .or_insert_with(|| {
+                if *handed_out < colors {
+                    *handed_out += 1;
+                    *handed_out - 1
+                } else {
+                    let mut hasher = DefaultHasher::new();
+                    text.hash(&mut hasher);
+                    (hasher.finish() % colors as u64) as u32
+                }
+            })
+    }
+}
+
+/// Splits the matches of the current search line on `line` into the parts
+/// coloured by `coloring`. Matches already in a colour group are kept.
+fn colored_matches(
+    line: &str,
+    matches: &[MatchPosition],
+    coloring: &Coloring,
+) -> Vec<MatchPosition> {
+    matches
+        .iter()
+        .flat_map(|m| match m.color_group {
+            None if m.stage == 0 => coloring.split(line, m),
+            _ => vec![m.clone()],
+        })
+        .collect()
+}
+
+/// Adds the matches of earlier search lines, the closest first, to the
+/// matches on a line. Each match is only shown where no closer search line
+/// matched, so a match may be split into several parts.
+fn with_earlier_matches(
+    line: &str,
+    matches: &[MatchPosition],
+    earlier_regexes: &[Matcher],
+) -> Vec<MatchPosition> {
+    let mut all_matches: Vec<MatchPosition> = matches
+        .iter()
+        .filter(|m| m.end > m.start)
+        .cloned()
+        .collect();
+    for (i, regex) in earlier_regexes.iter().enumerate() {
+        let mut taken: Vec<(u32, u32)> = all_matches.iter().map(|m| (m.start, m.end)).collect();
+        taken.sort_unstable();
+        for m in regex.find_matches(line) {
+            let mut start = m.start;
+            for &(taken_start, taken_end) in &taken {
+                if taken_start >= m.end {
+                    break;
+                }
+                if taken_end <= start {
+                    continue;
+                }
+                if taken_start > start {
+                    all_matches.push(MatchPosition {
+                        end: taken_start,
+                        stage: i as u32 + 1,
+                        start,
+                        ..m
+                    });
+                }
+                start = cmp::max(start, taken_end);
+            }
+            if start < m.end {
+                all_matches.push(MatchPosition {
+                    start,
+                    stage: i as u32 + 1,
+                    ..m
+                });
+            }
+        }
+    }
+    all_matches.sort_by_key(|m| m.start);
+    all_matches
+}
+
+/// Shows the parts of a line that are not matches as error output.
+fn as_error_line(line: StringWithColorIndexOrBreakLine) -> StringWithColorIndexOrBreakLine {
+    match line {
+        StringWithColorIndexOrBreakLine::StringWithColorIndex(parts) => {
+            StringWithColorIndexOrBreakLine::StringWithColorIndex(
+                parts
+                    .into_iter()
+                    .map(|part| match part {
+                        StringWithColorIndex::String(s) => StringWithColorIndex::ErrorString(s),
+                        part => part,
+                    })
+                    .collect(),
+            )
+        }
+        line => line,
+    }
+}
+
+/// Width of the gutter fitting the longest of the file names, including
+/// a separating space. Limited to a third of the content width.
+fn gutter_width(file_names: &[Option<String>], content_width: u32) -> u32 {
+    file_names
+        .iter()
+        .flatten()
+        .map(|name| cmp::min(name.chars().count() as u32 + 1, content_width / 3))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Pads the file name to fill the gutter, keeping the end of names that don't fit,
+/// as the line number is at the end.
+fn gutter(file_name: &str, gutter_width: u32) -> String {
+    let name_width = gutter_width.saturating_sub(1) as usize;
+    let name_length = file_name.chars().count();
+    let name = replace_tabs_with_spaces(
+        0,
+        &file_name
+            .chars()
+            .skip(name_length.saturating_sub(name_width))
+            .collect::<String>(),
+    );
+    format!("{:<width$} ", name, width = name_width)
+}
+
 /// Trims a single output line to fit the screen.
 /// Includes color information for each character.
 ///
@@ -138,7 +439,8 @@ fn output_lines_display_format(
 ///        └────────┘
 ///       content width
 ///
-fn trim_and_colorize_line<F: FnMut(&str) -> u32>(
+fn trim_and_colorize_line<F: FnMut(&MatchPosition, &str) -> u32>(
+    original_line: &str,
     line_with_match_ranges: &LineWithMatches,
     pager_x: u32,
     content_width: u32,
@@ -147,7 +449,6 @@ fn trim_and_colorize_line<F: FnMut(&str) -> u32>(
     let mut chars_to_drop = pager_x;
     let mut chars_to_take = content_width;
     let mut display_line: Vec<StringWithColorIndex> = vec![];
-    let original_line = &line_with_match_ranges.line;
     let mut cell_width = 0;
     let mut end_of_last_match = 0u32;
 
@@ -190,7 +491,7 @@ fn trim_and_colorize_line<F: FnMut(&str) -> u32>(
         // Process the current match on the line
         let string_with_match =
             &original_line[match_range.start as usize..match_range.end as usize];
-        let color = get_color(string_with_match);
+        let color = get_color(match_range, string_with_match);
 
         let string_with_match = replace_tabs_with_spaces(cell_width as u32, string_with_match);
         cell_width += string_with_match.chars().count();
@@ -224,7 +525,9 @@ fn trim_and_colorize_line<F: FnMut(&str) -> u32>(
 }
 
 /// Returns the same string where every tab is replaced with 1-4 spaces,
-/// depending on the horizontal position of the tab character.
+/// depending on the horizontal position of the tab character. Newlines and
+/// carriage returns, which lines only contain with `--null-data`, are shown
+/// as `\n` and `\r`.
 ///
 /// Example, with a single tab character in different places:
 /// ┌────────┐
@@ -244,6 +547,10 @@ fn replace_tabs_with_spaces(current_steps: u32, input_string: &str) -> String {
                 output_string.push(' ');
             }
             steps_taken += tab_width;
+        } else if s == '\n' || s == '\r' {
+            steps_taken += 2;
+            output_string.push('\\');
+            output_string.push(if s == '\n' { 'n' } else { 'r' });
         } else if s.len_utf8() > 1 {
             steps_taken += 1;
             output_string.push('_'); // replace unicode chars, until proper support
@@ -287,21 +594,66 @@ fn search_lines_display_format(
         let line = search_line.line_with_sensitivity_prefix();
         let last_column_no = cmp::min(line.len(), content_width as usize);
         let output_line = &line[0..last_column_no];
-        output_search_lines.push(SearchLine::new(
-            String::from(output_line),
-            search_line.context,
-            search_line.case_sensitive,
-            search_line.inverse,
-        ));
+        output_search_lines.push(SearchLine {
+            line: String::from(output_line),
+            ..search_line.clone()
+        });
     }
     output_search_lines
 }
 
+/// Each earlier branch of a union as one line, trimmed by width. Lines are
+/// only shown if there is room left after the current search lines.
+fn branch_lines_display_format(
+    input_window_height: u32,
+    search_lines: usize,
+    branches: &[Vec<SearchLine>],
+    content_width: u32,
+) -> Vec<String> {
+    let lines_to_take = cmp::min(
+        (input_window_height.saturating_sub(2) as usize).saturating_sub(search_lines),
+        branches.len(),
+    );
+    branches
+        .iter()
+        .skip(branches.len() - lines_to_take)
+        .map(|branch| {
+            let line = branch
+                .iter()
+                .map(|search_line| {
+                    let inverse = if search_line.inverse { "!" } else { "" };
+                    format!("{}{}", inverse, search_line.line_with_sensitivity_prefix())
+                })
+                .collect::<Vec<String>>()
+                .join(BRANCH_SEPARATOR);
+            line.chars()
+                .take(content_width.saturating_sub(BRANCH_PREFIX.len() as u32) as usize)
+                .collect()
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::igrepper::matcher::Engine;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn match_colors_are_spread_when_all_are_taken() {
+        let mut match_colors = MatchColors::default();
+        assert_eq!(match_colors.get(0, "a", 3), 0);
+        assert_eq!(match_colors.get(0, "b", 3), 1);
+        assert_eq!(match_colors.get(1, "b", 3), 0);
+        assert_eq!(match_colors.get(0, "a", 3), 0);
+        assert_eq!(match_colors.get(0, "c", 3), 2);
+        let later_colors: Vec<u32> = (0..30)
+            .map(|i| match_colors.get(0, &i.to_string(), 3))
+            .collect();
+        assert!((0..3).all(|color| later_colors.contains(&color)));
+        assert_eq!(match_colors.get(0, "17", 3), later_colors[17]);
+    }
+
     #[test]
     fn replace_tabs_with_spaces_zero_current_steps() {
         assert_eq!("    x", replace_tabs_with_spaces(0, "\tx"));
@@ -318,4 +670,32 @@ mod tests {
         assert_eq!(" x", replace_tabs_with_spaces(3, "\tx"));
         assert_eq!("    x", replace_tabs_with_spaces(4, "\tx"));
     }
+
+    #[test]
+    fn replace_newlines_with_escapes() {
+        assert_eq!("a\\r\\nb", replace_tabs_with_spaces(0, "a\r\nb"));
+        assert_eq!("\\n  x", replace_tabs_with_spaces(0, "\n\tx"));
+    }
+
+    #[test]
+    fn earlier_matches_only_where_closer_stages_did_not_match() {
+        let matches = vec![MatchPosition {
+            start: 3,
+            end: 5,
+            color_group: None,
+            stage: 0,
+        }];
+        let earlier_regexes = vec![
+            Matcher::new("a.{5}", Engine::Standard).unwrap(),
+            Matcher::new("x+", Engine::Standard).unwrap(),
+        ];
+        let all_matches = with_earlier_matches("xxabcdefx", &matches, &earlier_regexes)
+            .iter()
+            .map(|m| (m.start, m.end, m.stage))
+            .collect::<Vec<(u32, u32, u32)>>();
+        assert_eq!(
+            all_matches,
+            vec![(0, 2, 2), (2, 3, 1), (3, 5, 0), (5, 8, 1), (8, 9, 2)]
+        );
+    }
 }
@@ -7,6 +7,8 @@ pub struct RenderState {
     pub max_x: u32,
     pub input_window_height: u32,
     pub pager_window_height: u32,
+    /// Earlier branches of a union, shown above the current search lines.
+    pub output_branch_lines: Vec<String>,
     pub output_search_lines: Vec<SearchLine>,
     pub output_display_lines: Vec<StringWithColorIndexOrBreakLine>,
     pub status_line: String,
@@ -22,17 +24,27 @@ pub enum StringWithColorIndexOrBreakLine {
 pub enum StringWithColorIndex {
     MatchString((String, u32)), // u32 = color index
     String(String),
+    Gutter(String),      // information about the line, such as its file name
+    ErrorString(String), // text written to stderr by a command
 }
 
 #[derive(Debug, Clone)]
 pub enum Line {
     LineWithMatches(LineWithMatches),
+    /// A single match, shown without the rest of its line.
+    Match(LineWithMatches),
+    /// A match or line shown once for all `count` identical ones.
+    Unique {
+        count: usize,
+        line: Box<Line>,
+    },
     BreakLine,
 }
 
 #[derive(Debug, Clone)]
 pub struct LineWithMatches {
-    pub line: String,
+    /// Index of the line in the original input
+    pub source_index: usize,
     pub matches: Vec<MatchPosition>,
 }
 
@@ -40,4 +52,10 @@ pub struct LineWithMatches {
 pub struct MatchPosition {
     pub start: u32,
     pub end: u32,
+    /// Matches in the same group are shown in the same colour. Other matches
+    /// are coloured by their text.
+    pub color_group: Option<u32>,
+    /// How many search lines before the current one the match is from,
+    /// 0 for matches of the current search line.
+    pub stage: u32,
 }
@@ -1,2 +1,3 @@
+pub mod decoding;
 pub mod file_reading;
 pub mod igrepper;
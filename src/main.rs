@@ -2,15 +2,24 @@ extern crate clap;
 extern crate libc;
 
 use clap::Command;
-use inotify::{Inotify, WatchMask};
+use inotify::Inotify;
 use libc::close;
+use libc::dup;
 use libc::open;
 use std::env;
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
 
-use igrepper::file_reading::{self, SourceInput, SourceProducer};
+use igrepper::decoding::{Decoder, Encoding};
+use igrepper::file_reading::{SourceInput, SourceProducer};
 use igrepper::igrepper::igrepper;
+use igrepper::igrepper::output_generator::RecordMode;
+use igrepper::igrepper::state::{CaseMode, Context, SearchLine};
+use regex::Regex;
 
 const PARAMETER_ERROR: &str = "Data can only be passed by STDIN if no file parameter is specified";
+const FOLLOW_ERROR: &str = "Only a single file can be followed";
 const DEFAULT_EDITOR_COMMAND: [&str; 3] = ["vim", "-R", "-"];
 
 fn main() {
@@ -19,37 +28,112 @@ fn main() {
         .about("The interactive grepper")
         .arg(clap::arg!(-e --regex <REGEX> "Regular expression to preload"))
         .arg(clap::arg!(-c --context <CONTEXT> "Print CONTEXT num of output context"))
+        .arg(clap::arg!(-B --"before-context" <NUM> "Print NUM lines of context before matches, overriding --context"))
+        .arg(clap::arg!(-A --"after-context" <NUM> "Print NUM lines of context after matches, overriding --context"))
+        .arg(clap::arg!(--"filtered-context" "Take the context of later search lines from the output of the search line before, like a pipeline of grep commands, instead of from the input"))
         .arg(clap::arg!(-w --word "Preload the regular expression '\\S+'").conflicts_with("regex"))
         .arg(
             clap::arg!(-f --follow "Reload the file as it changes. Requires [file] to be set.")
                 .requires("FILE"),
         )
         .arg(
-            clap::arg!(<FILE> "Sets the input file to use. If not set, reads from stdin.")
+            clap::arg!(-r --recursive "Search directories recursively, skipping ignored, hidden and binary files")
+                .conflicts_with("follow"),
+        )
+        .arg(
+            clap::arg!(--cmd <COMMAND> "Run COMMAND with sh and search its output instead of a file")
+                .conflicts_with_all(["FILE", "follow", "recursive"]),
+        )
+        .arg(
+            clap::arg!(--interval <INTERVAL> "Re-run the command every INTERVAL, e.g. 2s or 500ms. F5 re-runs it at any time")
+                .requires("cmd")
+                .value_parser(parse_interval),
+        )
+        .arg(
+            clap::arg!(--"record-start" <REGEX> "Match multi-line records, each starting at a line matching REGEX")
+                .value_parser(|s: &str| Regex::new(s).map_err(|e| e.to_string())),
+        )
+        .arg(
+            clap::arg!(-p --paragraph "Match paragraphs separated by blank lines instead of lines")
+                .conflicts_with("record-start"),
+        )
+        .arg(
+            clap::arg!(--encoding <ENCODING> "Input encoding: utf-8 (default), latin1, utf-16le or utf-16be. A byte order mark overrides it")
+                .value_parser(clap::value_parser!(Encoding)),
+        )
+        .arg(
+            clap::arg!(--case <MODE> "Case matching: insensitive (default), sensitive or smart, which is insensitive unless the regex contains an uppercase letter")
+                .value_parser(clap::value_parser!(CaseMode)),
+        )
+        .arg(
+            clap::arg!(-z --"null-data" "Input lines end with NUL instead of newline, and so do lines copied or sent to the editor"),
+        )
+        .arg(
+            clap::arg!(<FILE> "Sets the input files to use. If not set, reads from stdin.")
+                .num_args(1..)
                 .required(false),
         )
         .get_matches();
 
+    let mut decoder = Decoder::new(
+        matches
+            .get_one::<Encoding>("encoding")
+            .copied()
+            .unwrap_or_default(),
+    );
+    if matches.get_flag("null-data") {
+        decoder = decoder.with_null_data();
+    }
+
     let is_tty = unsafe { libc::isatty(libc::STDIN_FILENO) } != 0;
-    let mut file_path: Option<&str> = None;
-    let source_producer: SourceProducer = if is_tty {
-        let path = matches.get_one::<String>("FILE").unwrap_or_else(|| {
-            eprintln!("{}", PARAMETER_ERROR);
+    let source_producer: SourceProducer = if let Some(command) = matches.get_one::<String>("cmd") {
+        if !is_tty {
+            // Keyboard input has to come from the terminal
+            drop(reopen_stdin());
+        }
+        SourceProducer {
+            input: SourceInput::Command(command.clone()),
+            decoder,
+        }
+    } else if is_tty && matches.get_flag("recursive") {
+        let paths: Vec<String> = match matches.get_many::<String>("FILE") {
+            Some(paths) => paths.cloned().collect(),
+            None => vec![String::from(".")],
+        };
+        SourceProducer {
+            input: SourceInput::Directories(paths),
+            decoder,
+        }
+    } else if is_tty {
+        let paths: Vec<String> = matches
+            .get_many::<String>("FILE")
+            .unwrap_or_else(|| {
+                eprintln!("{}", PARAMETER_ERROR);
+                std::process::exit(1);
+            })
+            .cloned()
+            .collect();
+        if paths.len() > 1 && matches.get_flag("follow") {
+            eprintln!("{}", FOLLOW_ERROR);
             std::process::exit(1);
-        });
-        file_path = Some(path);
+        }
         SourceProducer {
-            input: SourceInput::FilePath(path.to_string()),
+            input: if paths.len() == 1 {
+                SourceInput::FilePath(paths[0].clone())
+            } else {
+                SourceInput::FilePaths(paths)
+            },
+            decoder,
         }
     } else {
-        if matches.get_one::<String>("FILE").is_some() {
+        if matches.contains_id("FILE") {
             eprintln!("{}", PARAMETER_ERROR);
             std::process::exit(1);
         }
-        let source = file_reading::read_source_from_stdin();
-        reopen_stdin();
+        let piped_stdin = reopen_stdin();
         SourceProducer {
-            input: SourceInput::FullInput(source),
+            input: SourceInput::Stream(piped_stdin),
+            decoder,
         }
     };
 
@@ -57,6 +141,14 @@ fn main() {
         None => 0,
         Some(context_string) => context_string.parse::<u32>().unwrap(),
     };
+    let context_side = |name: &str| match matches.get_one::<String>(name) {
+        None => context,
+        Some(context_string) => context_string.parse::<u32>().unwrap(),
+    };
+    let context = Context::new(
+        context_side("before-context"),
+        context_side("after-context"),
+    );
 
     let initial_regex = if matches.get_flag("word") {
         Some("\\S+")
@@ -64,34 +156,74 @@ fn main() {
         matches.get_one::<String>("regex").map(|s| s.as_str())
     };
 
-    let inotify = if matches.get_flag("follow") {
-        let inotify = Inotify::init()
-            .expect("Failed to monitor file changes, error while initializing inotify instance");
-
-        inotify
-            .watches()
-            .add(
-                file_path.unwrap(),
-                WatchMask::MODIFY | WatchMask::CLOSE_WRITE,
-            )
-            .expect("Failed to add file watch");
-        Some(inotify)
+    let record_mode = if let Some(record_start) = matches.get_one::<Regex>("record-start") {
+        RecordMode::Start(record_start.clone())
+    } else if matches.get_flag("paragraph") {
+        RecordMode::Paragraphs
     } else {
-        None
+        RecordMode::Lines
+    };
+
+    let initial_search_line = SearchLine {
+        case_mode: match matches.get_one::<CaseMode>("case") {
+            Some(case_mode) => *case_mode,
+            None => get_case_mode(),
+        },
+        context,
+        ..SearchLine::new(String::from(initial_regex.unwrap_or("")), 0, false, false)
     };
 
+    let inotify =
+        if matches.get_flag("follow") {
+            Some(Inotify::init().expect(
+                "Failed to monitor file changes, error while initializing inotify instance",
+            ))
+        } else {
+            None
+        };
+
     let external_editor: Vec<String> = get_external_editor();
 
     igrepper(
         source_producer,
-        context,
-        initial_regex,
+        initial_search_line,
         inotify,
+        matches.get_one::<Duration>("interval").copied(),
+        record_mode,
+        matches.get_flag("filtered-context"),
         external_editor,
     )
     .unwrap();
 }
 
+/// Parses a duration such as `2s`, `500ms`, `1.5m` or `2`, which is in seconds.
+fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let unit_start = interval
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(interval.len());
+    let (number, unit) = interval.split_at(unit_start);
+    let seconds_per_unit = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        _ => return Err(format!("Unknown unit '{}', expected ms, s or m", unit)),
+    };
+    match number.parse::<f64>() {
+        Ok(n) if n > 0.0 && n.is_finite() => Ok(Duration::from_secs_f64(n * seconds_per_unit)),
+        _ => Err(format!("Invalid interval '{}'", interval)),
+    }
+}
+
+fn get_case_mode() -> CaseMode {
+    match env::var("IGREPPER_CASE") {
+        Ok(case_mode) => case_mode.parse().unwrap_or_else(|e| {
+            eprintln!("IGREPPER_CASE: {}", e);
+            std::process::exit(1);
+        }),
+        Err(_) => CaseMode::default(),
+    }
+}
+
 fn get_external_editor() -> Vec<String> {
     if let Ok(a) = env::var("IGREPPER_EDITOR") {
         let editor_command: Vec<String> =
@@ -106,15 +238,18 @@ fn get_external_editor() -> Vec<String> {
         .collect()
 }
 
-/// Close STDIN and open TTY as file descriptor 0.
-/// Used when all file input has been read, and STDIN should
-/// now come from the terminal.
-fn reopen_stdin() {
+/// Move STDIN to a new file descriptor and open TTY as file descriptor 0.
+/// Used so that keyboard input comes from the terminal, while the
+/// returned file keeps reading the piped input.
+fn reopen_stdin() -> File {
     unsafe {
+        let piped_stdin = dup(0);
+        assert!(piped_stdin >= 0, "Failed to duplicate stdin");
         let close_returncode = close(0);
         assert_eq!(close_returncode, 0, "Failed to close stdin");
         let ptr = "/dev/tty\0".as_ptr() as *const i8;
         let open_returncode = open(ptr, 0);
         assert_eq!(open_returncode, 0, "Failed to open /dev/tty");
+        File::from_raw_fd(piped_stdin)
     }
 }